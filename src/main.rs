@@ -1,13 +1,21 @@
 use async_executor::Executor;
 use async_recursion::async_recursion;
 use async_std::sync::{Arc, Mutex};
-use std::{collections::{HashMap, HashSet}, fmt, io};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
 
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use hex_literal::hex;
 use sha2::{Digest, Sha256};
 
 use log::{info, warn};
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, seq::SliceRandom, RngCore};
 use smol::future;
 use structopt::StructOpt;
 use structopt_toml::StructOptToml;
@@ -22,22 +30,71 @@ use darkfi::{
         expand_path,
         file::save_json_file,
         path::get_config_path,
-        serial::{Decodable, Encodable, ReadExt, SerialDecodable, SerialEncodable, WriteExt},
+        serial::{
+            deserialize, serialize, Decodable, Encodable, ReadExt, SerialDecodable,
+            SerialEncodable, VarInt, WriteExt,
+        },
         sleep,
     },
     Result,
 };
 
 type EventId = [u8; 32];
+// Stable identity of whoever created an event (e.g. their public key).
+// Unlike the display nick this doesn't change or collide.
+type AuthorId = [u8; 32];
+
+// Every node must start from the same root event, otherwise their trees
+// can never be joined together
+const GENESIS_TIMESTAMP: u64 = 1664582400000;
 
 #[derive(SerialEncodable, SerialDecodable)]
 struct Event {
     previous_event_hash: EventId,
     action: EventAction,
     timestamp: u64,
+    // Random, so identical messages sent at the same moment by two users
+    // still get distinct IDs
+    nonce: u64,
+    // Optional so events from nodes without an identity still decode
+    author: Option<AuthorId>,
 }
 
 impl Event {
+    fn with_author(mut self, author: AuthorId) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    // Grind the nonce until the ID has at least `difficulty` leading zero
+    // bits. Starts from the current nonce so it stays unpredictable.
+    fn mined(mut self, difficulty: u32) -> Self {
+        while leading_zero_bits(&self.hash()) < difficulty {
+            self.nonce = self.nonce.wrapping_add(1);
+        }
+        self
+    }
+
+    fn nick(&self) -> &str {
+        self.action.nick()
+    }
+
+    // CR, LF or NUL would break IRC line framing once the event is relayed
+    // to clients, letting the sender inject arbitrary commands. We reject
+    // rather than strip them since changing the event changes its ID.
+    fn has_forbidden_chars(&self) -> bool {
+        self.action.has_forbidden_chars()
+    }
+
+    fn header(&self) -> EventHeader {
+        EventHeader {
+            id: self.hash(),
+            previous_event_hash: self.previous_event_hash,
+            timestamp: self.timestamp,
+            action_type: self.action.type_id(),
+        }
+    }
+
     fn hash(&self) -> EventId {
         let mut bytes = Vec::new();
         self.encode(&mut bytes).expect("serialize failed!");
@@ -51,20 +108,196 @@ impl Event {
     }
 }
 
+// An event without its payload. Peers swap these first to learn the shape
+// of each other's tree, then fetch full events only for the IDs they lack.
+#[derive(SerialEncodable, SerialDecodable)]
+struct EventHeader {
+    id: EventId,
+    previous_event_hash: EventId,
+    timestamp: u64,
+    // The type ID byte EventAction is encoded with
+    action_type: u8,
+}
+
+// Signature algorithms an event can be signed with. The scheme byte is
+// part of the encoding so nodes using different schemes can share a
+// network while it migrates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SignatureScheme {
+    Ed25519,
+    // Reserved so secp256k1 signatures decode, but not verified yet
+    Secp256k1,
+}
+
+impl SignatureScheme {
+    fn type_id(self) -> u8 {
+        match self {
+            Self::Ed25519 => 0,
+            Self::Secp256k1 => 1,
+        }
+    }
+
+    // Whether we can produce signatures in this scheme, not just decode them
+    fn can_sign(self) -> bool {
+        self == Self::Ed25519
+    }
+}
+
+impl std::str::FromStr for SignatureScheme {
+    type Err = darkfi::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(Self::Ed25519),
+            "secp256k1" => Ok(Self::Secp256k1),
+            _ => Err(darkfi::Error::ParseFailed("Unknown signature scheme")),
+        }
+    }
+}
+
+// Largest signature we accept off the wire, for any scheme
+const MAX_SIGNATURE_LEN: u64 = 128;
+
+// A signature over an event ID by the event's author. Not yet carried in
+// Event itself, since adding it would change every event ID.
+struct EventSignature {
+    scheme: SignatureScheme,
+    bytes: Vec<u8>,
+}
+
+impl EventSignature {
+    fn sign(scheme: SignatureScheme, event: &Event, key: &SigningKey) -> Result<Self> {
+        let bytes = match scheme {
+            SignatureScheme::Ed25519 => key.sign(&event.hash()).to_bytes().to_vec(),
+            SignatureScheme::Secp256k1 => {
+                return Err(darkfi::Error::ParseFailed(
+                    "secp256k1 signing is not supported",
+                ))
+            }
+        };
+        Ok(Self { scheme, bytes })
+    }
+
+    fn verify(&self, event: &Event, author: &AuthorId) -> bool {
+        match self.scheme {
+            SignatureScheme::Ed25519 => {
+                let bytes: [u8; 64] = match self.bytes.as_slice().try_into() {
+                    Ok(bytes) => bytes,
+                    Err(_) => return false,
+                };
+                let key = match VerifyingKey::from_bytes(author) {
+                    Ok(key) => key,
+                    Err(_) => return false,
+                };
+                key.verify(&event.hash(), &Signature::from_bytes(&bytes))
+                    .is_ok()
+            }
+            SignatureScheme::Secp256k1 => false,
+        }
+    }
+}
+
+impl Encodable for EventSignature {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.scheme.type_id().encode(&mut s)?;
+        len += VarInt(self.bytes.len() as u64).encode(&mut s)?;
+        s.write_all(&self.bytes)?;
+        Ok(len + self.bytes.len())
+    }
+}
+
+impl Decodable for EventSignature {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let scheme = match d.read_u8()? {
+            0 => SignatureScheme::Ed25519,
+            1 => SignatureScheme::Secp256k1,
+            _ => return Err(darkfi::Error::ParseFailed("Bad scheme byte for signature")),
+        };
+        let bytes = decode_bounded_bytes(&mut d, MAX_SIGNATURE_LEN)?;
+        Ok(Self { scheme, bytes })
+    }
+}
+
 impl fmt::Debug for Event {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.action {
-            EventAction::PrivMsg(event) => write!(
-                f,
-                "PRIVMSG {}: {} ({})",
-                event.nick, event.msg, self.timestamp
-            ),
-        }
+        write!(f, "{:?} ({})", self.action, self.timestamp)
     }
 }
 
+// Most events carry a single action. A Batch groups several which are
+// stored as one node, so they are applied together or not at all even
+// across forks.
 enum EventAction {
     PrivMsg(PrivMsgEvent),
+    Batch(Vec<EventAction>),
+}
+
+// Upper bound on the number of actions in a single Batch
+const MAX_BATCH_LEN: u64 = 16;
+
+impl EventAction {
+    fn type_id(&self) -> u8 {
+        match self {
+            Self::PrivMsg(_) => 0,
+            Self::Batch(_) => 1,
+        }
+    }
+
+    // For a batch this is only the first action's nick, which is fine for
+    // display. Filters must use nicks(), each action names its own.
+    fn nick(&self) -> &str {
+        match self {
+            Self::PrivMsg(event) => &event.nick,
+            Self::Batch(actions) => actions.first().map_or("", |action| action.nick()),
+        }
+    }
+
+    fn nicks(&self) -> Vec<&str> {
+        match self {
+            Self::PrivMsg(event) => vec![&event.nick],
+            Self::Batch(actions) => actions.iter().flat_map(|action| action.nicks()).collect(),
+        }
+    }
+
+    fn has_forbidden_chars(&self) -> bool {
+        let is_forbidden = |c: char| matches!(c, '\r' | '\n' | '\0');
+        match self {
+            Self::PrivMsg(event) => {
+                event.nick.contains(is_forbidden) || event.msg.contains(is_forbidden)
+            }
+            Self::Batch(actions) => actions.iter().any(|action| action.has_forbidden_chars()),
+        }
+    }
+
+    fn decode_inner<D: io::Read>(d: &mut D, allow_batch: bool) -> Result<Self> {
+        let type_id = d.read_u8()?;
+        match type_id {
+            0 => Ok(Self::PrivMsg(PrivMsgEvent::decode(d)?)),
+            // Batches don't nest, which also keeps decoding depth bounded
+            1 if allow_batch => {
+                let count = VarInt::decode(&mut *d)?.0;
+                if count == 0 || count > MAX_BATCH_LEN {
+                    return Err(darkfi::Error::ParseFailed("Bad action count for Batch"));
+                }
+                let mut actions = Vec::new();
+                for _ in 0..count {
+                    actions.push(Self::decode_inner(d, false)?);
+                }
+                Ok(Self::Batch(actions))
+            }
+            _ => Err(darkfi::Error::ParseFailed("Bad type ID byte for Event")),
+        }
+    }
+}
+
+impl fmt::Debug for EventAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PrivMsg(event) => write!(f, "PRIVMSG {}: {}", event.nick, event.msg),
+            Self::Batch(actions) => f.debug_list().entries(actions).finish(),
+        }
+    }
 }
 
 impl Encodable for EventAction {
@@ -76,31 +309,75 @@ impl Encodable for EventAction {
                 len += event.encode(s)?;
                 Ok(len)
             }
+            Self::Batch(actions) => {
+                // Recursing through a trait object stops the writer type
+                // growing another &mut with every level
+                let s: &mut dyn io::Write = &mut s;
+                let mut len = 0;
+                len += 1u8.encode(&mut *s)?;
+                len += VarInt(actions.len() as u64).encode(&mut *s)?;
+                for action in actions {
+                    len += action.encode(&mut *s)?;
+                }
+                Ok(len)
+            }
         }
     }
 }
 
 impl Decodable for EventAction {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
-        let type_id = d.read_u8()?;
-        match type_id {
-            0 => Ok(Self::PrivMsg(PrivMsgEvent::decode(d)?)),
-            _ => Err(darkfi::Error::ParseFailed("Bad type ID byte for Event")),
-        }
+        Self::decode_inner(&mut d, true)
     }
 }
 
-#[derive(SerialEncodable, SerialDecodable)]
+#[derive(SerialEncodable)]
 struct PrivMsgEvent {
     nick: String,
     msg: String,
 }
 
+impl Decodable for PrivMsgEvent {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let nick = decode_bounded_string(&mut d)?;
+        let msg = decode_bounded_string(&mut d)?;
+        Ok(Self { nick, msg })
+    }
+}
+
+// Largest string field we accept off the wire
+const MAX_FIELD_LEN: u64 = 16 * 1024;
+
+// Like String::decode() but rejects length prefixes above MAX_FIELD_LEN
+fn decode_bounded_string<D: io::Read>(d: &mut D) -> Result<String> {
+    let bytes = decode_bounded_bytes(d, MAX_FIELD_LEN)?;
+    String::from_utf8(bytes).map_err(|_| darkfi::Error::ParseFailed("String field is not UTF-8"))
+}
+
+// Read a VarInt length prefixed byte string of at most max_len bytes.
+// Only allocates for bytes actually read, so a crafted length can't make
+// us reserve a huge buffer up front.
+fn decode_bounded_bytes<D: io::Read>(d: &mut D, max_len: u64) -> Result<Vec<u8>> {
+    let len = VarInt::decode(&mut *d)?.0;
+    if len > max_len {
+        return Err(darkfi::Error::ParseFailed("Field exceeds maximum length"));
+    }
+
+    let mut bytes = Vec::new();
+    let read = d.take(len).read_to_end(&mut bytes)?;
+    if read as u64 != len {
+        return Err(darkfi::Error::ParseFailed("Field is truncated"));
+    }
+    Ok(bytes)
+}
+
 struct EventNode {
     // Only current root has this set to None
     parent: Option<EventNodePtr>,
     event: Event,
     children: Mutex<Vec<EventNodePtr>>,
+    // Number of events between this one and the genesis event
+    height: u32,
 }
 
 type EventNodePtr = Arc<EventNode>;
@@ -108,37 +385,351 @@ type EventNodePtr = Arc<EventNode>;
 struct Model {
     // This is periodically updated so we discard old nodes
     current_root: EventId,
-    orphans: Vec<Event>,
+    // Events whose parent we don't have yet, keyed by that missing parent
+    orphans: HashMap<EventId, Vec<Event>>,
     event_map: HashMap<EventId, EventNodePtr>,
+    // Events added since the last reorganize. They are only processed once
+    // there are batch_size of them, or on an explicit flush().
+    pending: Vec<Event>,
+    batch_size: usize,
+    // A head change dropping more than this many events from the winning
+    // chain is reported as a large reorg
+    large_reorg_threshold: u32,
+    large_reorg_count: usize,
+    // Events refused by a hook, kept so an operator can readmit them after
+    // changing policy. Holds at most quarantine_size, oldest evicted first.
+    // 0 drops rejected events outright.
+    quarantine: VecDeque<QuarantinedEvent>,
+    quarantine_size: usize,
+    // Run in order on every added event. The first rejection wins.
+    hooks: Vec<AcceptanceHook>,
+    // Archive nodes only take events from peers and imports, never create
+    // their own
+    read_only: bool,
+    // Kept up to date as events are inserted so stats() is cheap
+    orphan_count: usize,
+    tip_count: usize,
+    max_height: u32,
+    // Events removed by purge(). They, and anything built on top of them,
+    // are refused if peers send them again.
+    purged: HashSet<EventId>,
+    // Where each accepted event entered this node. Local bookkeeping only,
+    // never sent to peers or hashed.
+    origins: HashMap<EventId, Origin>,
+    // Every node in insertion order, so views can pick up where they left
+    // off instead of walking the whole tree. Purged nodes are removed.
+    feed: BTreeMap<u64, EventNodePtr>,
+    feed_seqs: HashMap<EventId, u64>,
+    next_seq: u64,
+    // Callers of wait_for() blocked on an event we don't have yet
+    waiters: HashMap<EventId, Vec<async_channel::Sender<EventNodePtr>>>,
+    // Tip of the longest chain, i.e. the node with the greatest
+    // (height, timestamp, ID). This always matches find_head().
+    head: EventNodePtr,
+}
+
+#[derive(Debug, PartialEq)]
+enum ModelError {
+    UnknownEvent(EventId),
+    // The event is the current root, which can't be removed
+    ProtectedEvent(EventId),
+    // An acceptance hook refused the event, or it was purged
+    RejectedEvent(EventId, String),
+    // Neither the model nor the imported bundle has the event's parent
+    MissingParent(EventId),
+    // Walking parents from the event never reached the root
+    BrokenAncestry(EventId),
+    // This node doesn't create events, see Model::set_read_only()
+    ReadOnly,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownEvent(event_id) => write!(f, "unknown event {}", hex::encode(event_id)),
+            Self::ProtectedEvent(event_id) => {
+                write!(f, "event {} is protected", hex::encode(event_id))
+            }
+            Self::RejectedEvent(event_id, reason) => {
+                write!(f, "event {} rejected: {}", hex::encode(event_id), reason)
+            }
+            Self::MissingParent(event_id) => {
+                write!(f, "parent of event {} is missing", hex::encode(event_id))
+            }
+            Self::BrokenAncestry(event_id) => {
+                write!(
+                    f,
+                    "event {} does not lead back to the root",
+                    hex::encode(event_id)
+                )
+            }
+            Self::ReadOnly => write!(f, "node is read-only"),
+        }
+    }
+}
+
+// Transport an event arrived over, for tracing where a bad message came in
+#[derive(Clone, Debug, PartialEq)]
+enum Origin {
+    // Created by this node
+    Local,
+    // Relayed by the peer at this address
+    P2p(String),
+    // Loaded from a bundle by Model::import()
+    Import,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::P2p(addr) => write!(f, "p2p:{}", addr),
+            Self::Import => write!(f, "import"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum HeadComparison {
+    // The remote head isn't in our event map, so we can't compare
+    UnknownHead,
+    Known {
+        common_ancestor: EventId,
+        // Events on our side of the split, and on theirs
+        local_only: usize,
+        remote_only: usize,
+    },
+}
+
+// What Model::rebuild_index() had to change
+#[derive(Debug, Default, PartialEq)]
+struct IndexRepair {
+    // Children entries that were missing, extra or duplicated
+    links: usize,
+    // Events with a bad parent link or height, and their descendants
+    dropped: usize,
+}
+
+#[derive(Debug, PartialEq)]
+struct ModelStats {
+    event_count: usize,
+    orphan_count: usize,
+    head_height: u32,
+    root_height: u32,
+    // Tips other than the one on the longest chain
+    fork_count: usize,
+    large_reorg_count: usize,
 }
 
 impl Model {
     fn new() -> Self {
         let root_node = Arc::new(EventNode {
             parent: None,
-            event: Event {
-                previous_event_hash: [0u8; 32],
-                action: EventAction::PrivMsg(PrivMsgEvent {
-                    nick: "root".to_string(),
-                    msg: "Let there be dark".to_string(),
-                }),
-                timestamp: get_current_time(),
-            },
+            event: genesis_event(),
             children: Mutex::new(Vec::new()),
+            height: 0,
         });
         let root_node_id = root_node.event.hash();
 
-        let event_map = HashMap::from([(root_node_id.clone(), root_node)]);
+        let event_map = HashMap::from([(root_node_id.clone(), root_node.clone())]);
 
         Self {
             current_root: root_node_id,
-            orphans: Vec::new(),
+            orphans: HashMap::new(),
             event_map,
+            pending: Vec::new(),
+            batch_size: 1,
+            large_reorg_threshold: 10,
+            large_reorg_count: 0,
+            quarantine: VecDeque::new(),
+            quarantine_size: 0,
+            hooks: vec![Box::new(reject_forbidden_chars)],
+            read_only: false,
+            orphan_count: 0,
+            tip_count: 1,
+            max_height: 0,
+            purged: HashSet::new(),
+            origins: HashMap::new(),
+            feed: BTreeMap::from([(0, root_node.clone())]),
+            feed_seqs: HashMap::from([(root_node_id, 0)]),
+            next_seq: 1,
+            waiters: HashMap::new(),
+            head: root_node,
+        }
+    }
+
+    fn origin(&self, event_id: &EventId) -> Option<&Origin> {
+        self.origins.get(event_id)
+    }
+
+    fn add_hook(&mut self, hook: impl Fn(&Event) -> Acceptance + Send + Sync + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    // Remove every hook added since new(), e.g. to install a changed
+    // policy. The control character guard always stays.
+    fn clear_hooks(&mut self) {
+        self.hooks = vec![Box::new(reject_forbidden_chars)];
+    }
+
+    // The reason given by the first hook refusing the event, if any
+    fn rejection(&self, event: &Event) -> Option<String> {
+        self.hooks.iter().find_map(|hook| match hook(event) {
+            Acceptance::Accept => None,
+            Acceptance::Reject(reason) => Some(reason),
+        })
+    }
+
+    // During bulk sync, set this above 1 so a single reorganize pass
+    // resolves many events at once. Callers must flush() once the batch
+    // (or event loop tick) is done, otherwise the tail stays buffered.
+    fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    fn set_quarantine_size(&mut self, quarantine_size: usize) {
+        self.quarantine_size = quarantine_size;
+        self.quarantine.truncate(quarantine_size);
+    }
+
+    // Nodes inserted at or after the given sequence number, oldest first
+    fn inserted_since(&self, seq: u64) -> impl Iterator<Item = (u64, &EventNodePtr)> {
+        self.feed.range(seq..).map(|(seq, node)| (*seq, node))
+    }
+
+    fn quarantined(&self) -> impl Iterator<Item = &QuarantinedEvent> {
+        self.quarantine.iter()
+    }
+
+    // Take an event out of quarantine and add it, after the policy that
+    // refused it has changed. The hooks run again, and if one still refuses
+    // the event it stays quarantined with the new reason.
+    async fn readmit(&mut self, event_id: &EventId) -> std::result::Result<(), ModelError> {
+        let index = self
+            .quarantine
+            .iter()
+            .position(|entry| entry.event.hash() == *event_id)
+            .ok_or(ModelError::UnknownEvent(*event_id))?;
+        // It stays quarantined, in case the node is made writable again
+        if self.read_only && self.quarantine[index].origin == Origin::Local {
+            return Err(ModelError::ReadOnly);
         }
+        if let Some(reason) = self.rejection(&self.quarantine[index].event) {
+            warn!(
+                "Event {} is still rejected: {}",
+                hex::encode(event_id),
+                reason
+            );
+            self.quarantine[index].reason = reason.clone();
+            return Err(ModelError::RejectedEvent(*event_id, reason));
+        }
+        let entry = self.quarantine.remove(index).expect("index is in range");
+
+        info!(
+            "Readmitting event {} (was: {})",
+            hex::encode(event_id),
+            entry.reason
+        );
+        self.origins.entry(*event_id).or_insert(entry.origin);
+        self.pending.push(entry.event);
+        self.flush().await;
+        Ok(())
+    }
+
+    fn set_large_reorg_threshold(&mut self, threshold: u32) {
+        self.large_reorg_threshold = threshold;
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
     }
 
+    // Rejections are logged by add_from()
     async fn add(&mut self, event: Event) {
-        self.orphans.push(event);
+        let _ = self.add_from(event, Origin::Local).await;
+    }
+
+    async fn add_from(
+        &mut self,
+        event: Event,
+        origin: Origin,
+    ) -> std::result::Result<(), ModelError> {
+        if self.read_only && origin == Origin::Local {
+            warn!(
+                "Rejected event {} from {}: node is read-only",
+                hex::encode(event.hash()),
+                origin
+            );
+            return Err(ModelError::ReadOnly);
+        }
+
+        if let Some(reason) = self.rejection(&event) {
+            let event_id = event.hash();
+            warn!(
+                "Rejected event {} from {}: {}",
+                hex::encode(event_id),
+                origin,
+                reason
+            );
+            if self.quarantine_size > 0 {
+                if self.quarantine.len() == self.quarantine_size {
+                    self.quarantine.pop_front();
+                }
+                self.quarantine.push_back(QuarantinedEvent {
+                    event,
+                    origin,
+                    reason: reason.clone(),
+                });
+            }
+            return Err(ModelError::RejectedEvent(event_id, reason));
+        }
+
+        // The first source to deliver an event is the one remembered
+        self.origins.entry(event.hash()).or_insert(origin);
+        self.pending.push(event);
+        if self.pending.len() >= self.batch_size {
+            self.flush().await;
+        }
+        Ok(())
+    }
+
+    // Add a bundle of events, all or nothing. Every event must pass the
+    // acceptance hooks, must not be purged, and needs its parent in the
+    // model or the bundle, so that nothing is left orphaned. Returns how
+    // many events were new.
+    async fn import(&mut self, events: Vec<Event>) -> std::result::Result<usize, ModelError> {
+        let bundle: HashSet<EventId> = events.iter().map(|event| event.hash()).collect();
+        for event in &events {
+            let event_id = event.hash();
+            for hook in &self.hooks {
+                if let Acceptance::Reject(reason) = hook(event) {
+                    return Err(ModelError::RejectedEvent(event_id, reason));
+                }
+            }
+            let parent_id = &event.previous_event_hash;
+            if self.purged.contains(&event_id) || self.purged.contains(parent_id) {
+                return Err(ModelError::RejectedEvent(event_id, "purged".to_string()));
+            }
+            if !self.event_map.contains_key(parent_id) && !bundle.contains(parent_id) {
+                return Err(ModelError::MissingParent(event_id));
+            }
+        }
+
+        // Anything queued earlier goes first so it isn't mixed into the bundle
+        self.flush().await;
+        let inserted = bundle
+            .iter()
+            .filter(|event_id| !self.event_map.contains_key(*event_id))
+            .count();
+        for event in events {
+            self.origins.entry(event.hash()).or_insert(Origin::Import);
+            self.pending.push(event);
+        }
+        self.flush().await;
+        Ok(inserted)
+    }
+
+    async fn flush(&mut self) {
         self.reorganize().await;
     }
 
@@ -147,13 +738,19 @@ impl Model {
     // Also remove entries from event_map
 
     async fn reorganize(&mut self) {
-        let mut remaining_orphans = Vec::new();
-        for orphan in std::mem::take(&mut self.orphans) {
-            let prev_event = orphan.previous_event_hash.clone();
+        let mut resolvable = Vec::new();
+        for event in std::mem::take(&mut self.pending) {
+            let prev_event = event.previous_event_hash.clone();
+
+            if self.purged.contains(&prev_event) || self.purged.contains(&event.hash()) {
+                self.discard_purged(event.hash());
+                continue;
+            }
 
             // Parent does not yet exist
             if !self.event_map.contains_key(&prev_event) {
-                remaining_orphans.push(orphan);
+                self.orphans.entry(prev_event).or_default().push(event);
+                self.orphan_count += 1;
 
                 // BIGTODO #1:
                 // TODO: We need to fetch missing ancestors from the network
@@ -162,15 +759,30 @@ impl Model {
                 continue;
             }
 
+            resolvable.push(event);
+        }
+
+        let old_head = self.head.clone();
+
+        // Attaching an event releases the orphans waiting on it, which may
+        // in turn release their own children
+        while let Some(event) = resolvable.pop() {
+            let event_id = event.hash();
+            // We already have it, e.g. it was sent to us twice
+            if self.event_map.contains_key(&event_id) {
+                continue;
+            }
+
             let parent = self
                 .event_map
-                .get(&prev_event)
+                .get(&event.previous_event_hash)
                 .expect("logic error")
                 .clone();
             let node = Arc::new(EventNode {
                 parent: Some(parent.clone()),
-                event: orphan,
+                event,
                 children: Mutex::new(Vec::new()),
+                height: parent.height + 1,
             });
 
             // BIGTODO #2:
@@ -182,154 +794,2956 @@ impl Model {
             //    continue;
             //}
 
-            parent.children.lock().await.push(node.clone());
+            let mut siblings = parent.children.lock().await;
+            // Extending a tip doesn't add one, branching off the middle does
+            if !siblings.is_empty() {
+                self.tip_count += 1;
+            }
+            siblings.push(node.clone());
+            drop(siblings);
+
+            self.max_height = self.max_height.max(node.height);
+            if Self::head_key(&node) > Self::head_key(&self.head) {
+                self.head = node.clone();
+            }
+            for waiter in self.waiters.remove(&event_id).unwrap_or_default() {
+                let _ = waiter.try_send(node.clone());
+            }
+            self.feed.insert(self.next_seq, node.clone());
+            self.feed_seqs.insert(event_id, self.next_seq);
+            self.next_seq += 1;
             // Add node to the table
-            self.event_map.insert(node.event.hash(), node);
+            self.event_map.insert(event_id, node);
+
+            if let Some(waiting) = self.orphans.remove(&event_id) {
+                self.orphan_count -= waiting.len();
+                resolvable.extend(waiting);
+            }
+        }
+
+        if self.head.event.hash() != old_head.event.hash() {
+            info!("{}", self.tip_summary());
+            self.check_large_reorg(old_head);
         }
     }
 
-    fn get_root(&self) -> EventNodePtr {
-        let root_id = &self.current_root;
-        return self
-            .event_map
-            .get(root_id)
-            .expect("root ID is not in the event map!")
-            .clone();
+    // When a healed split swaps in a different chain, warn the operator so
+    // sudden scrollback changes have an explanation
+    fn check_large_reorg(&mut self, old_head: EventNodePtr) {
+        let ancestor = match self.common_ancestor(old_head.clone(), self.head.clone()) {
+            Ok(ancestor) => ancestor,
+            Err(err) => {
+                warn!("Can't measure reorg depth: {}", err);
+                return;
+            }
+        };
+        // Events on the old winning chain that are no longer on it
+        let rolled_back = old_head.height - ancestor.height;
+        if rolled_back > self.large_reorg_threshold {
+            self.large_reorg_count += 1;
+            warn!(
+                "Large reorg: {} events of the old chain were replaced, forked at {}. \
+                 History may appear reordered.",
+                rolled_back,
+                &hex::encode(ancestor.event.hash())[..8]
+            );
+        }
     }
 
-    // find_head
-    // -> recursively call itself
-    // -> + 1 for every recursion, return self if no children
-    // -> select max from returned values
-    // Gets the lead node with the maximal number of events counting from root
-    async fn find_head(&self) -> EventNodePtr {
-        let root = self.get_root();
-        Self::find_longest_chain(root, 0).await.1
+    // The same ordering find_longest_chain() uses to pick the head
+    fn head_key(node: &EventNode) -> (u32, u64, EventId) {
+        (node.height, node.event.timestamp, node.event.hash())
     }
 
-    #[async_recursion]
-    async fn find_longest_chain(parent_node: EventNodePtr, i: u32) -> (u32, EventNodePtr) {
-        let children = parent_node.children.lock().await;
-        if children.is_empty() {
-            return (i, parent_node.clone());
-        }
-        let mut current_max = 0;
-        let mut current_node = None;
-        for node in &*children {
-            let (grandchild_i, grandchild_node) =
-                Self::find_longest_chain(node.clone(), i + 1).await;
+    // One line describing the current head, for the daemon log
+    fn tip_summary(&self) -> String {
+        self.tip_summary_at(get_current_time())
+    }
+
+    fn tip_summary_at(&self, now: u64) -> String {
+        let head = &self.head;
+        let head_id = hex::encode(head.event.hash());
+        let age = now.saturating_sub(head.event.timestamp) / 1000;
 
-            if grandchild_i > current_max {
-                current_max = grandchild_i;
-                current_node = Some(grandchild_node.clone());
-            } else if grandchild_i == current_max {
-                // Break ties using the timestamp
-                if grandchild_node.event.timestamp
-                    > current_node
-                        .as_ref()
-                        .expect("current_node should be set!")
-                        .event
-                        .timestamp
-                {
-                    current_max = grandchild_i;
-                    current_node = Some(grandchild_node.clone());
+        let preview = match &head.event.action {
+            EventAction::PrivMsg(event) => {
+                let mut msg: String = event.msg.chars().take(32).collect();
+                if msg.len() < event.msg.len() {
+                    msg.push_str("...");
                 }
+                format!("<{}> {}", event.nick, msg)
             }
-        }
-        assert_ne!(current_max, 0);
-        (current_max, current_node.expect("internal logic error"))
+            EventAction::Batch(actions) => {
+                format!("<{}> [{} actions]", head.event.nick(), actions.len())
+            }
+        };
+
+        format!(
+            "head {} height={} {} ({}s ago)",
+            &head_id[..8],
+            head.height,
+            preview,
+            age
+        )
     }
 
-    fn find_height(&self, mut node: EventNodePtr) -> u32 {
-        let mut height = 0;
-        while node.event.hash() != self.current_root {
-            height += 1;
-            node = node.parent.as_ref().expect("non-root nodes should have a parent set").clone();
+    // Remove an event and everything built on top of it, e.g. a spam flood.
+    // Returns the number of events removed.
+    async fn purge(&mut self, event_id: &EventId) -> std::result::Result<usize, ModelError> {
+        let node = match self.event_map.get(event_id) {
+            Some(node) => node.clone(),
+            None => return Err(ModelError::UnknownEvent(*event_id)),
+        };
+        // Every other event descends from the root, so it's the only node
+        // on the path to itself
+        let parent = match &node.parent {
+            Some(parent) if *event_id != self.current_root => parent.clone(),
+            _ => return Err(ModelError::ProtectedEvent(*event_id)),
+        };
+
+        parent
+            .children
+            .lock()
+            .await
+            .retain(|child| child.event.hash() != *event_id);
+
+        let mut removed = 0;
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            stack.extend(node.children.lock().await.iter().cloned());
+            let node_id = node.event.hash();
+            self.event_map.remove(&node_id);
+            if let Some(seq) = self.feed_seqs.remove(&node_id) {
+                self.feed.remove(&seq);
+            }
+            self.discard_purged(node_id);
+            removed += 1;
         }
-        height
+
+        self.recount().await;
+        Ok(removed)
     }
 
-    fn find_ancestor_depth(&self, mut node_a: EventNodePtr, mut node_b: EventNodePtr) -> u32 {
-        let mut depth = 0;
-        while node_a.event.hash() != node_b.event.hash() {
-            depth += 1;
-            node_a = node_a.parent.as_ref().expect("non-root nodes should have a parent set").clone();
-            node_b = node_b.parent.as_ref().expect("non-root nodes should have a parent set").clone();
+    // Resolves once the event is in the model, right away if it already is.
+    // The waiter is registered before this returns, so the event may be
+    // added before the future is first polled. Resolves to None if the
+    // event is purged or the model dropped before it arrives.
+    //
+    // This takes &mut self to register the waiter, since the Model has no
+    // lock of its own. It isn't built on the daemon Subscriber because
+    // subscribing is async, and an event added before the subscription
+    // took effect would be missed.
+    fn wait_for(
+        &mut self,
+        event_id: EventId,
+    ) -> impl std::future::Future<Output = Option<EventNodePtr>> {
+        let (sender, receiver) = async_channel::bounded(1);
+        match self.event_map.get(&event_id) {
+            Some(node) => {
+                let _ = sender.try_send(node.clone());
+            }
+            None => {
+                // Futures dropped before their event arrived leave closed
+                // senders behind, so waits that never resolve can't pile up
+                self.waiters.retain(|_, senders| {
+                    senders.retain(|sender| !sender.is_closed());
+                    !senders.is_empty()
+                });
+                self.waiters.entry(event_id).or_default().push(sender);
+            }
         }
-        depth
+        async move { receiver.recv().await.ok() }
     }
 
-    async fn debug(&self) {
-        for (event_id, event_node) in &self.event_map {
-            let height = self.find_height(event_node.clone());
-            println!("{}: {:?} [height={}]", hex::encode(&event_id), event_node.event, height);
+    // Purge side branches whose highest event is more than
+    // min_depth_behind_head below the head. Events on the path from the
+    // root to the head are never touched. Like purge(), the removed events
+    // are refused if peers send them again. Returns the number removed.
+    async fn prune_losing_forks(&mut self, min_depth_behind_head: u32) -> usize {
+        // Head first, so each node's child on the winning chain is the one
+        // before it
+        let winning: Vec<EventNodePtr> = self.ancestors(self.head.clone()).collect();
+
+        let mut stale = Vec::new();
+        for (i, node) in winning.iter().enumerate() {
+            let winning_child = i.checked_sub(1).map(|i| winning[i].event.hash());
+            for branch in node.children.lock().await.iter() {
+                if Some(branch.event.hash()) == winning_child {
+                    continue;
+                }
+                let mut tip_height = branch.height;
+                let mut stack = vec![branch.clone()];
+                while let Some(node) = stack.pop() {
+                    tip_height = tip_height.max(node.height);
+                    stack.extend(node.children.lock().await.iter().cloned());
+                }
+                if self.head.height - tip_height > min_depth_behind_head {
+                    stale.push(branch.event.hash());
+                }
+            }
         }
 
-        println!("root: {}", hex::encode(&self.get_root().event.hash()));
-        println!(
-            "head: {}",
-            hex::encode(&self.find_head().await.event.hash())
-        );
+        let head_id = self.head.event.hash();
+        let mut removed = 0;
+        for branch_id in stale {
+            if self.is_ancestor(&branch_id, &head_id) {
+                warn!(
+                    "Not pruning {}, it is on the winning chain",
+                    hex::encode(branch_id)
+                );
+                continue;
+            }
+            match self.purge(&branch_id).await {
+                Ok(count) => removed += count,
+                Err(err) => warn!("Failed to prune {}: {}", hex::encode(branch_id), err),
+            }
+        }
+        removed
     }
+
+    // Remember a purged ID and drop any orphans that were waiting on it
+    fn discard_purged(&mut self, event_id: EventId) {
+        let mut stack = vec![event_id];
+        while let Some(event_id) = stack.pop() {
+            self.purged.insert(event_id);
+            self.waiters.remove(&event_id);
+            self.origins.remove(&event_id);
+            if let Some(waiting) = self.orphans.remove(&event_id) {
+                self.orphan_count -= waiting.len();
+                stack.extend(waiting.iter().map(|event| event.hash()));
+            }
+        }
+    }
+
+    // Recompute the incrementally kept counters and head from scratch,
+    // after removing events has invalidated them
+    async fn recount(&mut self) {
+        self.tip_count = 0;
+        self.max_height = 0;
+        self.head = self.get_root();
+        for node in self.event_map.values() {
+            if node.children.lock().await.is_empty() {
+                self.tip_count += 1;
+            }
+            self.max_height = self.max_height.max(node.height);
+            if Self::head_key(node) > Self::head_key(&self.head) {
+                self.head = node.clone();
+            }
+        }
+    }
+
+    // Recovery tool: rederive every children list from the parent links,
+    // in case a bug left the two out of step, then re-validate the tree
+    // from the root down. Events whose parent link disagrees with their
+    // previous_event_hash, or whose height is off, are dropped along with
+    // everything built on them. Peers can send them again.
+    async fn rebuild_index(&mut self) -> IndexRepair {
+        let mut expected: HashMap<EventId, Vec<EventNodePtr>> = HashMap::new();
+        for (event_id, node) in &self.event_map {
+            let parent = match &node.parent {
+                Some(parent) => parent,
+                None => continue,
+            };
+            let parent_id = parent.event.hash();
+            let linked = self
+                .event_map
+                .get(&parent_id)
+                .is_some_and(|known| Arc::ptr_eq(known, parent));
+            if parent_id != node.event.previous_event_hash || !linked {
+                warn!(
+                    "Event {} is linked to {} but its parent is {}",
+                    hex::encode(event_id),
+                    hex::encode(parent_id),
+                    hex::encode(node.event.previous_event_hash)
+                );
+                continue;
+            }
+            expected.entry(parent_id).or_default().push(node.clone());
+        }
+
+        // Only events reachable from the root with consistent heights stay
+        let mut valid = HashSet::from([self.current_root]);
+        let mut stack = vec![self.get_root()];
+        while let Some(node) = stack.pop() {
+            for child in expected.get(&node.event.hash()).into_iter().flatten() {
+                if child.height != node.height + 1 {
+                    warn!(
+                        "Event {} has height {}, expected {}",
+                        hex::encode(child.event.hash()),
+                        child.height,
+                        node.height + 1
+                    );
+                    continue;
+                }
+                valid.insert(child.event.hash());
+                stack.push(child.clone());
+            }
+        }
+
+        let mut repair = IndexRepair::default();
+        let broken: Vec<EventId> = self
+            .event_map
+            .keys()
+            .filter(|event_id| !valid.contains(*event_id))
+            .cloned()
+            .collect();
+        for event_id in broken {
+            self.event_map.remove(&event_id);
+            if let Some(seq) = self.feed_seqs.remove(&event_id) {
+                self.feed.remove(&seq);
+            }
+            self.origins.remove(&event_id);
+            repair.dropped += 1;
+        }
+
+        for (event_id, node) in &self.event_map {
+            let mut wanted = expected.remove(event_id).unwrap_or_default();
+            wanted.retain(|child| valid.contains(&child.event.hash()));
+            let mut children = node.children.lock().await;
+            let have: HashSet<EventId> = children.iter().map(|child| child.event.hash()).collect();
+            let want: HashSet<EventId> = wanted.iter().map(|child| child.event.hash()).collect();
+
+            let broken = have.symmetric_difference(&want).count() + children.len() - have.len();
+            if broken > 0 {
+                *children = wanted;
+                repair.links += broken;
+            }
+        }
+
+        if repair != IndexRepair::default() {
+            warn!(
+                "Rebuilt children index, fixed {} links and dropped {} events",
+                repair.links, repair.dropped
+            );
+            self.recount().await;
+        }
+        repair
+    }
+
+    fn stats(&self) -> ModelStats {
+        ModelStats {
+            event_count: self.event_map.len(),
+            orphan_count: self.orphan_count,
+            head_height: self.max_height,
+            root_height: self.get_root().height,
+            fork_count: self.tip_count - 1,
+            large_reorg_count: self.large_reorg_count,
+        }
+    }
+
+    fn get_root(&self) -> EventNodePtr {
+        let root_id = &self.current_root;
+        return self
+            .event_map
+            .get(root_id)
+            .expect("root ID is not in the event map!")
+            .clone();
+    }
+
+    // find_head
+    // -> recursively call itself
+    // -> + 1 for every recursion, return self if no children
+    // -> select max from returned values
+    // Gets the lead node with the maximal number of events counting from root
+    async fn find_head(&self) -> EventNodePtr {
+        let root = self.get_root();
+        Self::find_longest_chain(root, 0).await.1
+    }
+
+    // Has no panicking paths: a node whose children are all gone by the
+    // time we look is simply treated as a tip
+    #[async_recursion]
+    async fn find_longest_chain(parent_node: EventNodePtr, i: u32) -> (u32, EventNodePtr) {
+        let children = parent_node.children.lock().await;
+        let mut best: Option<(u32, EventNodePtr)> = None;
+        for node in &*children {
+            let (grandchild_i, grandchild_node) =
+                Self::find_longest_chain(node.clone(), i + 1).await;
+
+            // Break ties using the timestamp, then the event ID so that
+            // every node picks the same head regardless of arrival order
+            let is_better = match &best {
+                None => true,
+                Some((best_i, best_node)) => {
+                    (
+                        grandchild_i,
+                        grandchild_node.event.timestamp,
+                        grandchild_node.event.hash(),
+                    ) > (*best_i, best_node.event.timestamp, best_node.event.hash())
+                }
+            };
+            if is_better {
+                best = Some((grandchild_i, grandchild_node));
+            }
+        }
+        best.unwrap_or((i, parent_node.clone()))
+    }
+
+    // Gives up after as many steps as there are events, so corrupted links
+    // (a cycle, or a chain that never reaches the root) are reported
+    // instead of looping forever or panicking
+    fn find_height(&self, mut node: EventNodePtr) -> std::result::Result<u32, ModelError> {
+        let start = node.event.hash();
+        let mut height = 0;
+        while node.event.hash() != self.current_root {
+            if height as usize >= self.event_map.len() {
+                return Err(ModelError::BrokenAncestry(start));
+            }
+            height += 1;
+            node = node
+                .parent
+                .clone()
+                .ok_or(ModelError::BrokenAncestry(start))?;
+        }
+        Ok(height)
+    }
+
+    // For a stuck orphan: the IDs from `from` towards the root that aren't
+    // in the tree, following parent links of orphans we hold. The last ID
+    // is one we know nothing about, so it is the one to fetch. Past it the
+    // gap could be any length. Empty if `from` is already in the tree.
+    fn ancestors_missing(&self, from: &EventId) -> Vec<EventId> {
+        let orphan_parents = self.orphan_parents();
+        let mut missing = Vec::new();
+        let mut next = Some(*from);
+        while let Some(event_id) = next {
+            if self.event_map.contains_key(&event_id) {
+                break;
+            }
+            missing.push(event_id);
+            next = orphan_parents.get(&event_id).cloned();
+        }
+        missing
+    }
+
+    // Orphans grouped by the missing event they all wait on, directly or
+    // through other orphans. Fetching that one event resolves the whole
+    // group. Each group is sorted by ID.
+    fn orphan_roots(&self) -> HashMap<EventId, Vec<EventId>> {
+        let orphan_parents = self.orphan_parents();
+        let mut roots: HashMap<EventId, Vec<EventId>> = HashMap::new();
+        for orphan_id in orphan_parents.keys() {
+            let mut missing = orphan_id;
+            while let Some(parent_id) = orphan_parents.get(missing) {
+                missing = parent_id;
+            }
+            roots.entry(*missing).or_default().push(*orphan_id);
+        }
+        for group in roots.values_mut() {
+            group.sort();
+        }
+        roots
+    }
+
+    // Each held orphan's ID mapped to the ID of its parent
+    fn orphan_parents(&self) -> HashMap<EventId, EventId> {
+        self.orphans
+            .iter()
+            .flat_map(|(parent_id, waiting)| {
+                waiting.iter().map(move |event| (event.hash(), *parent_id))
+            })
+            .collect()
+    }
+
+    // Walk from node up to the current root, starting with node itself
+    fn ancestors(&self, node: EventNodePtr) -> Ancestors {
+        Ancestors {
+            root_id: self.current_root,
+            remaining: self.event_map.len(),
+            next: Some(node),
+        }
+    }
+
+    // A node counts as its own ancestor. Unknown events have no ancestors.
+    fn is_ancestor(&self, ancestor: &EventId, descendant: &EventId) -> bool {
+        let descendant = match self.event_map.get(descendant) {
+            Some(node) => node.clone(),
+            None => return false,
+        };
+        self.ancestors(descendant)
+            .any(|node| node.event.hash() == *ancestor)
+    }
+
+    // Deepest event that both nodes descend from. Like the other walks,
+    // each side climbs at most once per event in the model.
+    fn common_ancestor(
+        &self,
+        node_a: EventNodePtr,
+        node_b: EventNodePtr,
+    ) -> std::result::Result<EventNodePtr, ModelError> {
+        let mut a = (node_a.clone(), node_a.event.hash(), self.event_map.len());
+        let mut b = (node_b.clone(), node_b.event.hash(), self.event_map.len());
+        let step = |(node, start, remaining): &mut (EventNodePtr, EventId, usize)| {
+            if *remaining == 0 {
+                return Err(ModelError::BrokenAncestry(*start));
+            }
+            *remaining -= 1;
+            *node = node
+                .parent
+                .clone()
+                .ok_or(ModelError::BrokenAncestry(*start))?;
+            Ok(())
+        };
+
+        while a.0.height > b.0.height {
+            step(&mut a)?;
+        }
+        while b.0.height > a.0.height {
+            step(&mut b)?;
+        }
+        while a.0.event.hash() != b.0.event.hash() {
+            step(&mut a)?;
+            step(&mut b)?;
+        }
+        Ok(a.0)
+    }
+
+    // Events after ancestor up to and including descendant, oldest first.
+    // Empty if ancestor isn't actually an ancestor of descendant.
+    fn events_between(&self, ancestor: &EventId, descendant: EventNodePtr) -> Vec<EventNodePtr> {
+        let mut events = Vec::new();
+        for node in self.ancestors(descendant) {
+            if node.event.hash() == *ancestor {
+                events.reverse();
+                return events;
+            }
+            events.push(node);
+        }
+        Vec::new()
+    }
+
+    // Events on a's branch but not b's, and on b's but not a's, each
+    // oldest first. Both are empty when a and b are the same event.
+    fn fork_diff(
+        &self,
+        a: EventNodePtr,
+        b: EventNodePtr,
+    ) -> std::result::Result<(Vec<EventNodePtr>, Vec<EventNodePtr>), ModelError> {
+        let ancestor_id = self.common_ancestor(a.clone(), b.clone())?.event.hash();
+        Ok((
+            self.events_between(&ancestor_id, a),
+            self.events_between(&ancestor_id, b),
+        ))
+    }
+
+    // Compare our head against the head another node claims to have, to
+    // show where and how far the two have diverged
+    fn compare_head(
+        &self,
+        remote_head: &EventId,
+    ) -> std::result::Result<HeadComparison, ModelError> {
+        self.compare_tips(self.head.clone(), remote_head)
+    }
+
+    // compare_head() against any local node, e.g. a head seen earlier
+    fn compare_tips(
+        &self,
+        local: EventNodePtr,
+        remote_head: &EventId,
+    ) -> std::result::Result<HeadComparison, ModelError> {
+        let remote_node = match self.event_map.get(remote_head) {
+            Some(node) => node.clone(),
+            None => return Ok(HeadComparison::UnknownHead),
+        };
+        let ancestor = self.common_ancestor(local.clone(), remote_node.clone())?;
+        let ancestor_id = ancestor.event.hash();
+        Ok(HeadComparison::Known {
+            common_ancestor: ancestor_id,
+            local_only: self.events_between(&ancestor_id, local).len(),
+            remote_only: self.events_between(&ancestor_id, remote_node).len(),
+        })
+    }
+
+    // Every event in the tree, forks included, ordered by height then
+    // timestamp then ID. Any two nodes holding the same events produce the
+    // same list however the events reached them. Orphans aren't included.
+    fn replay_all(&self) -> Vec<EventNodePtr> {
+        let mut events: Vec<EventNodePtr> = self.event_map.values().cloned().collect();
+        events.sort_by_cached_key(|node| (node.height, node.event.timestamp, node.event.hash()));
+        events
+    }
+
+    // Proof that an event is part of the tree built on the current root,
+    // for light clients that only know the root ID. None for unknown
+    // events and for the root itself, which needs no proof.
+    fn inclusion_proof(&self, event_id: &EventId) -> Option<InclusionProof> {
+        let node = self.event_map.get(event_id)?.clone();
+        let steps: Vec<Vec<u8>> = self
+            .ancestors(node)
+            .take_while(|node| node.event.hash() != self.current_root)
+            .map(|node| serialize(&node.event))
+            .collect();
+        if steps.is_empty() {
+            return None;
+        }
+        Some(InclusionProof { steps })
+    }
+
+    // Bounded the same way as find_height()
+    fn find_ancestor_depth(
+        &self,
+        mut node_a: EventNodePtr,
+        mut node_b: EventNodePtr,
+    ) -> std::result::Result<u32, ModelError> {
+        let start = node_a.event.hash();
+        let mut depth = 0;
+        while node_a.event.hash() != node_b.event.hash() {
+            if depth as usize >= self.event_map.len() {
+                return Err(ModelError::BrokenAncestry(start));
+            }
+            depth += 1;
+            node_a = node_a
+                .parent
+                .clone()
+                .ok_or(ModelError::BrokenAncestry(start))?;
+            node_b = node_b
+                .parent
+                .clone()
+                .ok_or(ModelError::BrokenAncestry(start))?;
+        }
+        Ok(depth)
+    }
+
+    async fn debug(&self) {
+        for line in self.dump(usize::MAX) {
+            info!("{}", line);
+        }
+    }
+
+    // Summary lines plus at most max_events events, highest first, so a
+    // dump of a large model stays short
+    fn dump(&self, max_events: usize) -> Vec<String> {
+        let mut lines = vec![
+            format!("root: {}", hex::encode(self.current_root)),
+            format!("head: {}", hex::encode(self.head.event.hash())),
+            format!("{:?}", self.stats()),
+        ];
+
+        let mut nodes: Vec<&EventNodePtr> = self.event_map.values().collect();
+        nodes.sort_by_cached_key(|node| std::cmp::Reverse(Self::head_key(node)));
+        for node in nodes.iter().take(max_events) {
+            let height = match self.find_height((*node).clone()) {
+                Ok(height) => height.to_string(),
+                Err(_) => "?".to_string(),
+            };
+            lines.push(format!(
+                "{}: {:?} [height={}]",
+                hex::encode(node.event.hash()),
+                node.event,
+                height
+            ));
+        }
+        if nodes.len() > max_events {
+            lines.push(format!("... {} more events", nodes.len() - max_events));
+        }
+        lines
+    }
+}
+
+// Encoded events from the proven event back to a child of the root. An
+// event ID is the hash of its encoding, so a verifier can rehash each
+// step and check it links to the next without holding the tree.
+struct InclusionProof {
+    steps: Vec<Vec<u8>>,
+}
+
+impl InclusionProof {
+    // The event this proves, if the proof is well formed
+    fn event_id(&self) -> Option<EventId> {
+        let event: Event = deserialize(self.steps.first()?).ok()?;
+        Some(event.hash())
+    }
+}
+
+// Check every step names the hash of the one after it as its parent, and
+// the last one names the trusted root
+fn verify_inclusion(proof: &InclusionProof, root: &EventId) -> bool {
+    if proof.steps.is_empty() {
+        return false;
+    }
+    let mut expected_parent = None;
+    for step in proof.steps.iter().rev() {
+        let event: Event = match deserialize(step) {
+            Ok(event) => event,
+            Err(_) => return false,
+        };
+        if event.previous_event_hash != expected_parent.unwrap_or(*root) {
+            return false;
+        }
+        expected_parent = Some(event.hash());
+    }
+    true
+}
+
+struct QuarantinedEvent {
+    event: Event,
+    origin: Origin,
+    // Why the hook refused it
+    reason: String,
+}
+
+enum Acceptance {
+    Accept,
+    Reject(String),
+}
+
+// Called on each event in Model::add() before it is stored, so operators
+// can plug in their own filters
+type AcceptanceHook = Box<dyn Fn(&Event) -> Acceptance + Send + Sync>;
+
+fn reject_forbidden_chars(event: &Event) -> Acceptance {
+    if event.has_forbidden_chars() {
+        return Acceptance::Reject("contains control characters".to_string());
+    }
+    Acceptance::Accept
+}
+
+fn leading_zero_bits(event_id: &EventId) -> u32 {
+    let mut bits = 0;
+    for byte in event_id {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+// Proof of work makes flooding the network expensive without needing to
+// know who anyone is. Each extra bit of difficulty doubles the work.
+fn require_pow(difficulty: u32) -> impl Fn(&Event) -> Acceptance {
+    move |event| {
+        let bits = leading_zero_bits(&event.hash());
+        if bits < difficulty {
+            return Acceptance::Reject(format!(
+                "proof of work too weak ({} of {} bits)",
+                bits, difficulty
+            ));
+        }
+        Acceptance::Accept
+    }
+}
+
+// Only store and relay events whose action types are listed, e.g. to keep
+// a small node to plain messages. A batch is checked along with every
+// action inside it.
+fn allow_action_types(allowed: HashSet<u8>) -> impl Fn(&Event) -> Acceptance {
+    move |event| {
+        let mut actions = vec![&event.action];
+        if let EventAction::Batch(inner) = &event.action {
+            actions.extend(inner);
+        }
+        let disallowed = actions
+            .into_iter()
+            .find(|action| !allowed.contains(&action.type_id()));
+        if let Some(action) = disallowed {
+            return Acceptance::Reject(format!("action type {} not relayed", action.type_id()));
+        }
+        Acceptance::Accept
+    }
+}
+
+// Keeps nicks short enough for IRC clients to display
+fn limit_nick_len(max_len: usize) -> impl Fn(&Event) -> Acceptance {
+    move |event| {
+        let too_long = |action: &EventAction| action.nick().chars().count() > max_len;
+        let rejected = match &event.action {
+            EventAction::Batch(actions) => actions.iter().any(too_long),
+            action => too_long(action),
+        };
+        if rejected {
+            return Acceptance::Reject(format!("nick longer than {} characters", max_len));
+        }
+        Acceptance::Accept
+    }
+}
+
+// Coarse moderation installed as an acceptance hook. A blocked nick or author is
+// always rejected. If any allow entries are configured, only events whose
+// nick or author is listed get through.
+#[derive(Default)]
+struct AuthorFilter {
+    blocked_nicks: HashSet<String>,
+    blocked_authors: HashSet<AuthorId>,
+    allowed_nicks: HashSet<String>,
+    allowed_authors: HashSet<AuthorId>,
+}
+
+impl AuthorFilter {
+    fn from_settings(settings: &Args) -> Result<Self> {
+        Ok(Self {
+            blocked_nicks: settings.blocked_nicks.iter().cloned().collect(),
+            blocked_authors: parse_author_ids(&settings.blocked_authors)?,
+            allowed_nicks: settings.allowed_nicks.iter().cloned().collect(),
+            allowed_authors: parse_author_ids(&settings.allowed_authors)?,
+        })
+    }
+
+    // Every nick in a batch is checked, so a blocked one can't hide
+    // behind an allowed first action
+    fn check(&self, event: &Event) -> Acceptance {
+        let nicks = event.action.nicks();
+        let author = event.author.as_ref();
+
+        if let Some(nick) = nicks
+            .iter()
+            .find(|nick| self.blocked_nicks.contains(**nick))
+        {
+            return Acceptance::Reject(format!("nick {} is blocked", nick));
+        }
+        if let Some(author) = author {
+            if self.blocked_authors.contains(author) {
+                return Acceptance::Reject(format!("author {} is blocked", hex::encode(author)));
+            }
+        }
+
+        if self.allowed_nicks.is_empty() && self.allowed_authors.is_empty() {
+            return Acceptance::Accept;
+        }
+        if author.is_some_and(|author| self.allowed_authors.contains(author)) {
+            return Acceptance::Accept;
+        }
+        match nicks
+            .iter()
+            .find(|nick| !self.allowed_nicks.contains(**nick))
+        {
+            Some(nick) => Acceptance::Reject(format!("nick {} is not on the allowlist", nick)),
+            None => Acceptance::Accept,
+        }
+    }
+}
+
+fn parse_author_ids(hex_ids: &[String]) -> Result<HashSet<AuthorId>> {
+    let mut author_ids = HashSet::new();
+    for hex_id in hex_ids {
+        let bytes = hex::decode(hex_id)
+            .map_err(|_| darkfi::Error::ParseFailed("Author ID is not valid hex"))?;
+        let author_id: AuthorId = bytes
+            .try_into()
+            .map_err(|_| darkfi::Error::ParseFailed("Author ID must be 32 bytes"))?;
+        author_ids.insert(author_id);
+    }
+    Ok(author_ids)
+}
+
+struct Ancestors {
+    root_id: EventId,
+    // No real walk yields more nodes than the model holds
+    remaining: usize,
+    next: Option<EventNodePtr>,
+}
+
+impl Iterator for Ancestors {
+    type Item = EventNodePtr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next.take()?;
+        if self.remaining == 0 {
+            warn!(
+                "Ancestor walk passed {} without reaching the root",
+                hex::encode(node.event.hash())
+            );
+            return None;
+        }
+        self.remaining -= 1;
+        if node.event.hash() != self.root_id {
+            self.next = node.parent.clone();
+        }
+        Some(node)
+    }
+}
+
+pub const CONFIG_FILE: &str = "ircd_config.toml";
+pub const CONFIG_FILE_CONTENTS: &str = include_str!("../ircd_config.toml");
+
+#[derive(Clone, Debug, serde::Deserialize, StructOpt, StructOptToml)]
+#[serde(default)]
+#[structopt(name = "ircd")]
+pub struct Args {
+    #[structopt(long)]
+    pub config: Option<String>,
+
+    /// Increase verbosity
+    #[structopt(short, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Path to the node's identity keypair, created on first run
+    #[structopt(long, default_value = "~/.config/darkfi/ircd_identity")]
+    pub identity_path: String,
+
+    /// Run the built-in regression checks and exit
+    #[structopt(long)]
+    pub selftest: bool,
+
+    /// Keep up to this many rejected events for review (0 = drop them)
+    #[structopt(long, default_value = "0")]
+    pub quarantine_size: usize,
+
+    /// Warn when a reorg replaces more than this many winning events
+    #[structopt(long, default_value = "10")]
+    pub large_reorg_threshold: u32,
+
+    /// Tag nicks used by more than one author with a short author ID
+    #[structopt(long)]
+    pub disambiguate_nicks: bool,
+
+    /// Archive node: only take events from peers, never create them
+    #[structopt(long)]
+    pub read_only: bool,
+
+    /// Append replayed messages to this file (empty = disabled)
+    #[structopt(long, default_value = "")]
+    pub chatlog_path: String,
+
+    /// Rotate the chat log once it reaches this many bytes (0 = never)
+    #[structopt(long, default_value = "10485760")]
+    pub chatlog_max_size: u64,
+
+    /// Scheme used to sign our events: "ed25519" ("secp256k1" is not supported yet)
+    #[structopt(long, default_value = "ed25519")]
+    pub signature_scheme: String,
+
+    /// Reject events whose nick is longer than this many characters
+    #[structopt(long, default_value = "32")]
+    pub max_nick_len: usize,
+
+    /// Only accept these action type IDs (empty = all)
+    #[structopt(long)]
+    pub relay_action_types: Vec<u8>,
+
+    /// Leading zero bits required in event IDs (0 = no proof of work)
+    #[structopt(long, default_value = "0")]
+    pub pow_difficulty: u32,
+
+    /// Replay order for late events: "timestamp" or "append-stable"
+    #[structopt(long, default_value = "timestamp")]
+    pub replay_order: String,
+
+    /// Don't replay messages older than this many seconds (0 = forever)
+    #[structopt(long, default_value = "0")]
+    pub message_ttl: u64,
+
+    /// Reject events from these nicks
+    #[structopt(long)]
+    pub blocked_nicks: Vec<String>,
+
+    /// Reject events from these author IDs (hex)
+    #[structopt(long)]
+    pub blocked_authors: Vec<String>,
+
+    /// Only accept events from these nicks (and allowed_authors)
+    #[structopt(long)]
+    pub allowed_nicks: Vec<String>,
+
+    /// Only accept events from these author IDs (hex) (and allowed_nicks)
+    #[structopt(long)]
+    pub allowed_authors: Vec<String>,
+}
+
+// Load the node's Ed25519 signing key, generating and saving a new one
+// if the file doesn't exist yet. Its public key is our author ID. The
+// scheme is checked first, so a bad setting never writes a key file.
+fn load_identity(scheme: SignatureScheme, path: &Path) -> Result<SigningKey> {
+    if !scheme.can_sign() {
+        return Err(darkfi::Error::ParseFailed(
+            "Only ed25519 signing is supported",
+        ));
+    }
+    if path.exists() {
+        let contents = fs::read_to_string(path)?;
+        let bytes = hex::decode(contents.trim())
+            .map_err(|_| darkfi::Error::ParseFailed("Identity file is not valid hex"))?;
+        let secret: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| darkfi::Error::ParseFailed("Identity key must be 32 bytes"))?;
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+
+    let signing_key = SigningKey::generate(&mut OsRng);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Only the owner may read the secret key
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(hex::encode(signing_key.to_bytes()).as_bytes())?;
+    file.sync_all()?;
+
+    info!("Generated new identity at {:?}", path);
+    Ok(signing_key)
+}
+
+fn get_current_time() -> u64 {
+    let start = std::time::SystemTime::now();
+    start
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+        .try_into()
+        .unwrap()
+}
+
+fn genesis_event() -> Event {
+    Event {
+        previous_event_hash: [0u8; 32],
+        action: EventAction::PrivMsg(PrivMsgEvent {
+            nick: "root".to_string(),
+            msg: "Let there be dark".to_string(),
+        }),
+        timestamp: GENESIS_TIMESTAMP,
+        nonce: 0,
+        author: None,
+    }
+}
+
+fn create_message(previous_event_hash: EventId, nick: &str, msg: &str, timestamp: u64) -> Event {
+    Event {
+        previous_event_hash,
+        action: EventAction::PrivMsg(PrivMsgEvent {
+            nick: nick.to_string(),
+            msg: msg.to_string(),
+        }),
+        timestamp,
+        nonce: OsRng.next_u64(),
+        author: None,
+    }
+}
+
+// Two in-process nodes joined by an in-memory channel which stands in for
+// the network. Used to check that divergent models converge after syncing.
+struct SimNode {
+    model: Model,
+    sender: async_channel::Sender<Vec<u8>>,
+    receiver: async_channel::Receiver<Vec<u8>>,
+}
+
+impl SimNode {
+    fn pair() -> (Self, Self) {
+        let (a_sender, b_receiver) = async_channel::unbounded();
+        let (b_sender, a_receiver) = async_channel::unbounded();
+        let node_a = Self {
+            model: Model::new(),
+            sender: a_sender,
+            receiver: a_receiver,
+        };
+        // Node B resolves orphans in batches so both modes get compared
+        let mut node_b = Self {
+            model: Model::new(),
+            sender: b_sender,
+            receiver: b_receiver,
+        };
+        node_b.model.set_batch_size(8);
+        (node_a, node_b)
+    }
+
+    fn inventory(&self) -> HashSet<EventId> {
+        self.model.event_map.keys().cloned().collect()
+    }
+
+    // Anti-entropy: send every event the peer is missing. The map is
+    // unordered so children regularly arrive before their parents.
+    async fn push_missing(&self, peer_inventory: &HashSet<EventId>) {
+        for (event_id, event_node) in &self.model.event_map {
+            if peer_inventory.contains(event_id) {
+                continue;
+            }
+            self.sender
+                .send(serialize(&event_node.event))
+                .await
+                .expect("peer hung up");
+        }
+    }
+
+    fn headers(&self) -> Vec<EventHeader> {
+        self.model
+            .event_map
+            .values()
+            .map(|event_node| event_node.event.header())
+            .collect()
+    }
+
+    // Send the full events for the given IDs, as a reply to a fetch
+    async fn push_events(&self, event_ids: &[EventId]) {
+        for event_id in event_ids {
+            let event_node = &self.model.event_map[event_id];
+            self.sender
+                .send(serialize(&event_node.event))
+                .await
+                .expect("peer hung up");
+        }
+    }
+
+    async fn receive_all(&mut self) -> Result<()> {
+        while let Ok(bytes) = self.receiver.try_recv() {
+            let event: Event = deserialize(&bytes)?;
+            // A rejected event is logged and doesn't hold up the rest
+            let _ = self
+                .model
+                .add_from(event, Origin::P2p("sim-peer".to_string()))
+                .await;
+        }
+        self.model.flush().await;
+        Ok(())
+    }
+}
+
+// Shape of the divergent history injected into a pair of nodes: a chain
+// both nodes share, then one branch per node forking off its end.
+struct SyncScenario {
+    shared: u32,
+    branch_a: u32,
+    branch_b: u32,
+}
+
+impl SyncScenario {
+    async fn run(&self) -> Result<()> {
+        let (mut node_a, mut node_b) = SimNode::pair();
+        let root_id = node_a.model.get_root().event.hash();
+
+        // Node B learns the shared history from node A before they split
+        let fork = add_chain(&mut node_a.model, root_id, "shared", self.shared).await;
+        node_a.push_missing(&node_b.inventory()).await;
+        node_b.receive_all().await?;
+        assert_eq!(node_b.model.find_head().await.event.hash(), fork);
+
+        add_chain(&mut node_a.model, fork, "alice", self.branch_a).await;
+        add_chain(&mut node_b.model, fork, "bob", self.branch_b).await;
+
+        let inventory_a = node_a.inventory();
+        let inventory_b = node_b.inventory();
+        node_a.push_missing(&inventory_b).await;
+        node_b.push_missing(&inventory_a).await;
+        node_a.receive_all().await?;
+        node_b.receive_all().await?;
+
+        assert!(node_a.model.orphans.is_empty());
+        assert!(node_b.model.orphans.is_empty());
+        assert_eq!(node_a.inventory(), node_b.inventory());
+        assert_eq!(
+            node_a.inventory().len() as u32,
+            1 + self.shared + self.branch_a + self.branch_b
+        );
+        assert_eq!(
+            node_a.model.find_head().await.event.hash(),
+            node_b.model.find_head().await.event.hash()
+        );
+        // Node A made the shared and alice events and learnt bob's from B
+        for node in node_a.model.event_map.values() {
+            let expected = match node.event.nick() {
+                "root" => None,
+                "bob" => Some(Origin::P2p("sim-peer".to_string())),
+                _ => Some(Origin::Local),
+            };
+            assert_eq!(node_a.model.origin(&node.event.hash()), expected.as_ref());
+        }
+        // Events arrived in a different order on each node, but the full
+        // transcript must come out byte for byte the same
+        let transcript = |model: &Model| -> Vec<u8> {
+            model
+                .replay_all()
+                .iter()
+                .flat_map(|node| serialize(&node.event))
+                .collect()
+        };
+        assert_eq!(transcript(&node_a.model), transcript(&node_b.model));
+        // The incrementally tracked head must agree with the full walk
+        assert_eq!(
+            node_a.model.head.event.hash(),
+            node_a.model.find_head().await.event.hash()
+        );
+        assert_eq!(
+            node_b.model.head.event.hash(),
+            node_b.model.find_head().await.event.hash()
+        );
+        Ok(())
+    }
+}
+
+async fn check_purge() -> Result<()> {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let keep = add_chain(&mut model, root_id, "alice", 2).await;
+
+    // A spam event with two branches of its own on top
+    let spam = create_message(root_id, "spam", "flood", GENESIS_TIMESTAMP + 1);
+    let spam_id = spam.hash();
+    let resend = serialize(&spam);
+    model.add(spam).await;
+    let tip_a = add_chain(&mut model, spam_id, "spam", 3).await;
+    let tip_b = add_chain(&mut model, spam_id, "spam", 1).await;
+    assert_eq!(model.event_map.len(), 8);
+
+    assert_eq!(model.purge(&spam_id).await, Ok(5));
+    for event_id in [spam_id, tip_a, tip_b] {
+        assert!(!model.event_map.contains_key(&event_id));
+    }
+    assert_eq!(model.event_map.len(), 3);
+    assert_eq!(model.head.event.hash(), keep);
+    assert_eq!(model.stats().fork_count, 0);
+    assert_eq!(
+        model.purge(&root_id).await,
+        Err(ModelError::ProtectedEvent(root_id))
+    );
+
+    // Peers sending it again, or building on it, get nowhere
+    model.add(deserialize(&resend)?).await;
+    model
+        .add(create_message(
+            spam_id,
+            "spam",
+            "again",
+            GENESIS_TIMESTAMP + 9,
+        ))
+        .await;
+    assert_eq!(model.event_map.len(), 3);
+    assert!(!model.event_map.contains_key(&spam_id));
+    Ok(())
+}
+
+async fn check_is_ancestor() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    let tip_a = add_chain(&mut model, fork, "alice", 2).await;
+    let tip_b = add_chain(&mut model, fork, "bob", 1).await;
+
+    assert!(model.is_ancestor(&root_id, &tip_a));
+    assert!(model.is_ancestor(&fork, &tip_b));
+    assert!(model.is_ancestor(&tip_a, &tip_a));
+    // Siblings aren't related, and it doesn't hold downwards
+    assert!(!model.is_ancestor(&tip_a, &tip_b));
+    assert!(!model.is_ancestor(&tip_a, &fork));
+    assert!(!model.is_ancestor(&root_id, &[0xee; 32]));
+}
+
+// The counters behind stats() are kept up to date incrementally, so
+// check them against each kind of change
+async fn check_stats() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let stats = |event_count, orphan_count, head_height, fork_count| ModelStats {
+        event_count,
+        orphan_count,
+        head_height,
+        root_height: 0,
+        fork_count,
+        large_reorg_count: 0,
+    };
+    assert_eq!(model.stats(), stats(1, 0, 0, 0));
+
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    add_chain(&mut model, fork, "alice", 1).await;
+    assert_eq!(model.stats(), stats(4, 0, 3, 0));
+
+    // bob's longer branch takes over
+    let tip_b = add_chain(&mut model, fork, "bob", 3).await;
+    assert_eq!(model.find_head().await.event.hash(), tip_b);
+    assert_eq!(model.stats(), stats(7, 0, 5, 1));
+
+    let missing = create_message(root_id, "carol", "missing", GENESIS_TIMESTAMP);
+    model
+        .add(create_message(
+            missing.hash(),
+            "carol",
+            "orphan",
+            GENESIS_TIMESTAMP,
+        ))
+        .await;
+    assert_eq!(model.stats(), stats(7, 1, 5, 1));
+
+    assert_eq!(model.prune_losing_forks(1).await, 1);
+    assert_eq!(model.stats(), stats(6, 1, 5, 0));
+}
+
+// The same message sent at the same moment by two users must not be
+// dropped as a duplicate
+async fn check_nonce() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    let first = create_message(root_id, "alice", "hi", GENESIS_TIMESTAMP + 1);
+    let mut second = create_message(root_id, "alice", "hi", GENESIS_TIMESTAMP + 1);
+    second.nonce = first.nonce.wrapping_add(1);
+    let (first_id, second_id) = (first.hash(), second.hash());
+    assert_ne!(first_id, second_id);
+
+    model.add(first).await;
+    model.add(second).await;
+    assert!(model.event_map.contains_key(&first_id));
+    assert!(model.event_map.contains_key(&second_id));
+}
+
+// A late event with an old timestamp goes before what was already shown
+// in timestamp order, and after it in append-stable order
+async fn check_replay_order() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let first = create_message(root_id, "alice", "first", GENESIS_TIMESTAMP + 2);
+    let second = create_message(first.hash(), "alice", "second", GENESIS_TIMESTAMP + 3);
+    let late = create_message(root_id, "bob", "late", GENESIS_TIMESTAMP + 1);
+    model.add(first).await;
+    model.add(second).await;
+
+    let mut timestamp = View::new(ReplayOrder::Timestamp, 0);
+    let mut append_stable = View::new(ReplayOrder::AppendStable, 0);
+    timestamp.process(&model);
+    append_stable.process(&model);
+
+    model.add(late).await;
+    for view in [&mut timestamp, &mut append_stable] {
+        let delivered = view.process(&model);
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].event.nick(), "bob");
+    }
+
+    let msgs = |view: &View| -> Vec<String> {
+        view.transcript()
+            .skip(1)
+            .map(|node| view.format(&node.event))
+            .collect()
+    };
+    assert_eq!(
+        msgs(&timestamp),
+        ["<bob> late", "<alice> first", "<alice> second"]
+    );
+    assert_eq!(
+        msgs(&append_stable),
+        ["<alice> first", "<alice> second", "<bob> late"]
+    );
+}
+
+async fn check_hooks() {
+    let mut model = Model::new();
+    model.add_hook(|event| match event.nick() {
+        "spammer" => Acceptance::Reject("first".to_string()),
+        _ => Acceptance::Accept,
+    });
+    let root_id = model.get_root().event.hash();
+
+    let spam = create_message(root_id, "spammer", "buy now", GENESIS_TIMESTAMP + 1);
+    let spam_id = spam.hash();
+    model.add(spam).await;
+    let event = create_message(root_id, "alice", "hi", GENESIS_TIMESTAMP + 1);
+    let alice_id = event.hash();
+    model.add(event).await;
+    assert!(!model.event_map.contains_key(&spam_id));
+    assert!(model.event_map.contains_key(&alice_id));
+
+    // Every hook must accept, not just the first
+    model.add_hook(|_| Acceptance::Reject("second".to_string()));
+    let event = create_message(root_id, "bob", "hi", GENESIS_TIMESTAMP + 1);
+    let bob_id = event.hash();
+    model.add(event).await;
+    assert!(!model.event_map.contains_key(&bob_id));
+}
+
+// Line breaks would let a message inject IRC commands when relayed
+async fn check_forbidden_chars() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let injected = [
+        create_message(root_id, "alice", "hi\r\nQUIT :bye", GENESIS_TIMESTAMP + 1),
+        create_message(root_id, "alice\r\nNICK bob", "hi", GENESIS_TIMESTAMP + 1),
+        create_message(root_id, "alice", "nul\0", GENESIS_TIMESTAMP + 1),
+    ];
+    for event in injected {
+        let event_id = event.hash();
+        assert!(matches!(
+            reject_forbidden_chars(&event),
+            Acceptance::Reject(_)
+        ));
+        model.add(event).await;
+        assert!(!model.event_map.contains_key(&event_id));
+    }
+
+    let clean = create_message(root_id, "alice", "hi", GENESIS_TIMESTAMP + 1);
+    let clean_id = clean.hash();
+    model.add(clean).await;
+    assert!(model.event_map.contains_key(&clean_id));
+}
+
+async fn check_tip_summary() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let timestamp = GENESIS_TIMESTAMP + 1000;
+    // Fixed nonces, so the head ID in the summary is stable
+    let message = |parent, nick: &str, msg: &str| Event {
+        nonce: 0,
+        ..create_message(parent, nick, msg, timestamp)
+    };
+
+    // alice's tip loses to bob's longer branch, whose message is cut short
+    let alice = message(root_id, "alice", "short");
+    let bob = message(root_id, "bob", "short");
+    let long = message(
+        bob.hash(),
+        "bob",
+        "a message well over thirty-two characters",
+    );
+    for event in [alice, bob, long] {
+        model.add(event).await;
+    }
+    assert_eq!(model.stats().fork_count, 1);
+
+    assert_eq!(
+        model.tip_summary_at(timestamp + 42_500),
+        "head 1a399445 height=2 <bob> a message well over thirty-two c... (42s ago)"
+    );
+}
+
+async fn check_compare_head() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    let head = add_chain(&mut model, fork, "alice", 3).await;
+    let other = add_chain(&mut model, fork, "bob", 2).await;
+    assert_eq!(model.head.event.hash(), head);
+
+    let known = |common_ancestor, local_only, remote_only| {
+        Ok(HeadComparison::Known {
+            common_ancestor,
+            local_only,
+            remote_only,
+        })
+    };
+    let compare = |remote: &EventId| model.compare_head(remote);
+
+    // Equal, ahead of an older point on our own chain, and diverged
+    assert_eq!(compare(&head), known(head, 0, 0));
+    assert_eq!(compare(&fork), known(fork, 3, 0));
+    assert_eq!(compare(&other), known(fork, 3, 2));
+    assert_eq!(compare(&[0xee; 32]), Ok(HeadComparison::UnknownHead));
+
+    // Behind: a node still on the fork point sees our head 3 events ahead
+    let behind = model.compare_tips(model.event_map[&fork].clone(), &head);
+    assert_eq!(behind, known(fork, 0, 3));
+}
+
+// A chain arriving newest first is held as orphans until its oldest event
+// arrives, then attaches in one go. Unrelated orphans stay where they are.
+async fn check_orphan_cascade(interleave: bool) {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    let a = create_message(root_id, "alice", "a", GENESIS_TIMESTAMP + 1);
+    let b = create_message(a.hash(), "alice", "b", GENESIS_TIMESTAMP + 2);
+    let c = create_message(b.hash(), "alice", "c", GENESIS_TIMESTAMP + 3);
+    let chain = [a.hash(), b.hash(), c.hash()];
+
+    let mut unrelated = 0;
+    for event in [c, b, a] {
+        if interleave {
+            let missing_parent = create_message(root_id, "bob", "missing", GENESIS_TIMESTAMP);
+            let orphan = create_message(missing_parent.hash(), "bob", "orphan", GENESIS_TIMESTAMP);
+            model.add(orphan).await;
+            unrelated += 1;
+        }
+        // Nothing attaches before the oldest event arrives
+        assert!(!model.event_map.contains_key(&chain[2]));
+        model.add(event).await;
+    }
+
+    for (height, event_id) in chain.iter().enumerate() {
+        assert_eq!(model.event_map[event_id].height, height as u32 + 1);
+    }
+    assert_eq!(model.find_head().await.event.hash(), chain[2]);
+    assert_eq!(model.stats().orphan_count, unrelated);
+    assert_eq!(model.orphans.len(), unrelated);
+}
+
+// `len` encoded events, each on a random earlier one, in random order so
+// that most arrive before their parent
+fn shuffled_tree(len: usize) -> Vec<Vec<u8>> {
+    let mut event_ids = vec![genesis_event().hash()];
+    let mut encoded = Vec::new();
+    for i in 0..len {
+        let parent = event_ids[OsRng.next_u64() as usize % event_ids.len()];
+        let msg = format!("message {}", i);
+        let event = create_message(parent, "alice", &msg, GENESIS_TIMESTAMP + 1 + i as u64);
+        event_ids.push(event.hash());
+        encoded.push(serialize(&event));
+    }
+    encoded.shuffle(&mut OsRng);
+    encoded
+}
+
+async fn insert_all(model: &mut Model, encoded: &[Vec<u8>]) -> Result<()> {
+    for bytes in encoded {
+        model.add(deserialize(bytes)?).await;
+    }
+    model.flush().await;
+    Ok(())
+}
+
+// Batching only changes when orphans get resolved, not the tree they
+// end up in
+async fn check_batched_insert() -> Result<()> {
+    let encoded = shuffled_tree(500);
+    let mut per_event = Model::new();
+    let mut batched = Model::new();
+    batched.set_batch_size(32);
+    insert_all(&mut per_event, &encoded).await?;
+    insert_all(&mut batched, &encoded).await?;
+
+    let heights = |model: &Model| -> HashMap<EventId, u32> {
+        model
+            .event_map
+            .iter()
+            .map(|(event_id, node)| (*event_id, node.height))
+            .collect()
+    };
+    assert_eq!(per_event.event_map.len(), 501);
+    assert_eq!(heights(&per_event), heights(&batched));
+    assert_eq!(per_event.head.event.hash(), batched.head.event.hash());
+    assert!(per_event.orphans.is_empty() && batched.orphans.is_empty());
+    Ok(())
+}
+
+// Not a check, only logs how long a bulk insert of shuffled events takes
+// with and without batching
+async fn bench_bulk_insert(len: usize) -> Result<()> {
+    let encoded = shuffled_tree(len);
+    for batch_size in [1, 256] {
+        let mut model = Model::new();
+        model.set_batch_size(batch_size);
+        let start = std::time::Instant::now();
+        insert_all(&mut model, &encoded).await?;
+        info!(
+            "Bulk insert of {} events with batch size {}: {:?}",
+            len,
+            batch_size,
+            start.elapsed()
+        );
+    }
+    Ok(())
+}
+
+// First run generates a key only the owner can read, later runs load it
+fn check_identity() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("ircd-identity-{}", OsRng.next_u64()));
+    let path = dir.join("identity");
+
+    // A scheme we can't sign with fails without creating the file
+    assert!(load_identity(SignatureScheme::Secp256k1, &path).is_err());
+    assert!(!path.exists());
+
+    let generated = load_identity(SignatureScheme::Ed25519, &path)?;
+    let mode = fs::metadata(&path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let loaded = load_identity(SignatureScheme::Ed25519, &path)?;
+    assert_eq!(loaded.to_bytes(), generated.to_bytes());
+    assert_eq!(loaded.verifying_key(), generated.verifying_key());
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+async fn check_prune_losing_forks() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    // Long chain with stale side branches at heights 2, 5 and 8
+    let mut winning = vec![root_id];
+    for i in 0..10 {
+        let parent = *winning.last().unwrap();
+        winning.push(add_chain(&mut model, parent, &format!("main{}", i), 1).await);
+    }
+    add_chain(&mut model, winning[2], "old", 1).await;
+    let middle = add_chain(&mut model, winning[5], "middle", 2).await;
+    let recent = add_chain(&mut model, winning[8], "recent", 1).await;
+    let head_id = model.head.event.hash();
+    assert_eq!(head_id, winning[10]);
+
+    // Only the branch ending at height 3 is more than 3 behind the head
+    assert_eq!(model.prune_losing_forks(3).await, 1);
+    assert!(model.event_map.contains_key(&middle));
+    assert!(model.event_map.contains_key(&recent));
+    assert!(winning.iter().all(|id| model.event_map.contains_key(id)));
+    assert_eq!(model.head.event.hash(), head_id);
+
+    assert_eq!(model.prune_losing_forks(0).await, 3);
+    assert_eq!(model.event_map.len(), winning.len());
+    assert_eq!(model.stats().fork_count, 0);
+}
+
+async fn check_inclusion_proof() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    let tip = add_chain(&mut model, fork, "alice", 3).await;
+    add_chain(&mut model, fork, "bob", 1).await;
+
+    let proof = model.inclusion_proof(&tip).expect("tip is in the model");
+    assert_eq!(proof.steps.len(), 5);
+    assert_eq!(proof.event_id(), Some(tip));
+    assert!(verify_inclusion(&proof, &root_id));
+    assert!(!verify_inclusion(&proof, &fork));
+
+    // Editing a step's message changes its hash, so the link from its
+    // child breaks. Byte 41 is the first character of the message text.
+    let mut tampered = InclusionProof {
+        steps: proof.steps.clone(),
+    };
+    tampered.steps[3][41] ^= 1;
+    assert!(deserialize::<Event>(&tampered.steps[3]).is_ok());
+    assert!(!verify_inclusion(&tampered, &root_id));
+
+    // Dropping a step breaks the chain too
+    let mut truncated = InclusionProof {
+        steps: proof.steps.clone(),
+    };
+    truncated.steps.remove(2);
+    assert!(!verify_inclusion(&truncated, &root_id));
+
+    assert!(model.inclusion_proof(&root_id).is_none());
+    assert!(model.inclusion_proof(&[0u8; 32]).is_none());
+}
+
+async fn check_message_ttl() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let now = get_current_time();
+    let old = create_message(root_id, "alice", "old", now - 120_000);
+    let old_id = old.hash();
+    let recent = create_message(old_id, "alice", "recent", now);
+    let recent_id = recent.hash();
+    model.add(old).await;
+    model.add(recent).await;
+
+    // One minute, so the genesis event and the old message have expired
+    let mut view = View::new(ReplayOrder::Timestamp, 60_000);
+    let replayed: Vec<EventId> = view
+        .process(&model)
+        .iter()
+        .map(|node| node.event.hash())
+        .collect();
+    assert_eq!(replayed, [recent_id]);
+    // They stay in the model for sync
+    assert_eq!(model.event_map.len(), 3);
+    assert!(view.process(&model).is_empty());
+
+    // A longer fork takes over. Its expired event isn't sent after the
+    // reorg, or later.
+    let expired = create_message(old_id, "bob", "expired", now - 90_000);
+    let fresh = create_message(expired.hash(), "bob", "fresh", now);
+    let fresh_id = fresh.hash();
+    model.add(expired).await;
+    model.add(fresh).await;
+    assert_eq!(model.head.event.hash(), fresh_id);
+    let delivered: Vec<EventId> = view
+        .process_reorg(&model, &recent_id)
+        .iter()
+        .map(|node| node.event.hash())
+        .collect();
+    assert_eq!(delivered, [fresh_id]);
+    assert!(view.process(&model).is_empty());
+}
+
+async fn check_reorg_delivery() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 3).await;
+    let old_head = add_chain(&mut model, fork, "alice", 2).await;
+
+    let mut view = View::new(ReplayOrder::AppendStable, 0);
+    assert_eq!(view.process(&model).len(), 6);
+
+    // A longer branch from the fork point takes over
+    add_chain(&mut model, fork, "bob", 3).await;
+    let delivered = view.process_reorg(&model, &old_head);
+    assert_eq!(delivered.len(), 3);
+    assert!(delivered.iter().all(|node| node.event.nick() == "bob"));
+
+    // Nothing is sent twice, by either path
+    assert!(view.process_reorg(&model, &old_head).is_empty());
+    assert!(view.process(&model).is_empty());
+    assert_eq!(view.transcript().len(), 9);
+}
+
+// Processing after every add must end with the same transcript as one
+// pass at the end, including across a purge
+async fn check_incremental_view() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let mut incremental = View::new(ReplayOrder::Timestamp, 0);
+
+    let fork = add_chain(&mut model, root_id, "alice", 3).await;
+    incremental.process(&model);
+    let spam = add_chain(&mut model, fork, "spam", 2).await;
+    incremental.process(&model);
+    add_chain(&mut model, fork, "bob", 2).await;
+    model.purge(&spam).await.unwrap();
+    // Older than everything shown so far, so it sorts to the front
+    model
+        .add(create_message(root_id, "carol", "late", GENESIS_TIMESTAMP))
+        .await;
+    assert_eq!(incremental.process(&model).len(), 3);
+    assert!(incremental.process(&model).is_empty());
+
+    let mut fresh = View::new(ReplayOrder::Timestamp, 0);
+    fresh.process(&model);
+    let mut expected: Vec<EventNodePtr> = model.event_map.values().cloned().collect();
+    expected.sort_by_cached_key(|node| (node.event.timestamp, node.event.hash()));
+    let ids = |nodes: Vec<&EventNodePtr>| -> Vec<EventId> {
+        nodes.iter().map(|node| node.event.hash()).collect()
+    };
+    let expected = ids(expected.iter().collect());
+    // The purged spam tip was already shown, so only it differs
+    let shown: Vec<EventId> = ids(incremental.transcript().collect())
+        .into_iter()
+        .filter(|id| *id != spam)
+        .collect();
+    assert_eq!(shown, expected);
+    assert_eq!(ids(fresh.transcript().collect()), expected);
+}
+
+// Not a check, only logs the cost of delivering after every add on a
+// linear history. The full pass walks and sorts every event on each call,
+// as View::process did before it read from the insertion feed.
+async fn bench_view_delivery(len: u32) {
+    let mut model = Model::new();
+    let mut view = View::new(ReplayOrder::Timestamp, 0);
+    let mut parent = model.get_root().event.hash();
+    let mut incremental = std::time::Duration::ZERO;
+    let mut full_pass = std::time::Duration::ZERO;
+    for i in 0..len {
+        let event = create_message(parent, "alice", "hi", GENESIS_TIMESTAMP + 1 + i as u64);
+        parent = event.hash();
+        model.add(event).await;
+
+        let start = std::time::Instant::now();
+        view.process(&model);
+        incremental += start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut transcript: Vec<EventNodePtr> = model.event_map.values().cloned().collect();
+        transcript.sort_by_cached_key(|node| (node.event.timestamp, node.event.hash()));
+        full_pass += start.elapsed();
+    }
+    assert_eq!(view.transcript().len(), len as usize + 1);
+    info!(
+        "Delivering after each of {} adds: incremental {:?}, full pass {:?}",
+        len, incremental, full_pass
+    );
+}
+
+async fn check_rebuild_index() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    let tip_a = add_chain(&mut model, fork, "alice", 2).await;
+    add_chain(&mut model, fork, "bob", 1).await;
+    assert_eq!(model.rebuild_index().await, IndexRepair::default());
+
+    // Lose one link, duplicate another and add a bogus one
+    let fork_node = model.event_map[&fork].clone();
+    let lost = fork_node.children.lock().await.remove(0);
+    let root = model.get_root();
+    let first = root.children.lock().await[0].clone();
+    root.children.lock().await.push(first);
+    root.children.lock().await.push(lost.clone());
+
+    let repair = model.rebuild_index().await;
+    assert_eq!(
+        repair,
+        IndexRepair {
+            links: 3,
+            dropped: 0
+        }
+    );
+    assert_eq!(fork_node.children.lock().await.len(), 2);
+    assert_eq!(root.children.lock().await.len(), 1);
+    assert_eq!(model.find_head().await.event.hash(), tip_a);
+    assert_eq!(model.rebuild_index().await, IndexRepair::default());
+
+    // An event hung off the wrong parent, and one with a bogus height
+    // which also takes its child down with it
+    let corrupt = |parent: &EventNodePtr, previous: EventId, height| {
+        Arc::new(EventNode {
+            parent: Some(parent.clone()),
+            event: create_message(previous, "mallory", "corrupt", GENESIS_TIMESTAMP + 9),
+            children: Mutex::new(Vec::new()),
+            height,
+        })
+    };
+    let tip_node = model.event_map[&tip_a].clone();
+    let mislinked = corrupt(&root, fork, 1);
+    let too_high = corrupt(&tip_node, tip_a, tip_node.height + 5);
+    let above = corrupt(&too_high, too_high.event.hash(), too_high.height + 1);
+    root.children.lock().await.push(mislinked.clone());
+    tip_node.children.lock().await.push(too_high.clone());
+    too_high.children.lock().await.push(above.clone());
+    for node in [mislinked, too_high, above] {
+        model.event_map.insert(node.event.hash(), node);
+    }
+    assert_eq!(model.find_head().await.event.nick(), "mallory");
+
+    let repair = model.rebuild_index().await;
+    assert_eq!(
+        repair,
+        IndexRepair {
+            links: 2,
+            dropped: 3
+        }
+    );
+    assert_eq!(model.event_map.len(), 6);
+    assert_eq!(model.head.event.hash(), tip_a);
+    assert_eq!(model.find_head().await.event.hash(), tip_a);
+    assert_eq!(model.rebuild_index().await, IndexRepair::default());
+}
+
+async fn check_ancestors_missing() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    // c -> b -> a -> root, where only a is never received
+    let a = create_message(root_id, "alice", "a", GENESIS_TIMESTAMP + 1);
+    let b = create_message(a.hash(), "alice", "b", GENESIS_TIMESTAMP + 2);
+    let c = create_message(b.hash(), "alice", "c", GENESIS_TIMESTAMP + 3);
+    let (a_id, b_id, c_id) = (a.hash(), b.hash(), c.hash());
+    model.add(c).await;
+    model.add(b).await;
+
+    assert_eq!(model.ancestors_missing(&c_id), [c_id, b_id, a_id]);
+    assert_eq!(model.ancestors_missing(&b_id), [b_id, a_id]);
+    // Nothing is known beyond an ID no orphan links from
+    assert_eq!(model.ancestors_missing(&a_id), [a_id]);
+    assert!(model.ancestors_missing(&root_id).is_empty());
+
+    model.add(a).await;
+    assert!(model.ancestors_missing(&c_id).is_empty());
+}
+
+async fn check_dump() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let head = add_chain(&mut model, root_id, "alice", 9).await;
+
+    let lines = model.dump(3);
+    assert_eq!(lines.len(), 3 + 3 + 1);
+    assert!(lines[3].starts_with(&hex::encode(head)));
+    assert_eq!(lines[6], "... 7 more events");
+    assert_eq!(model.dump(usize::MAX).len(), 3 + 10);
+}
+
+async fn check_author_filter() {
+    let blocked: AuthorId = [0xbb; 32];
+    let filter = AuthorFilter {
+        blocked_authors: HashSet::from([blocked]),
+        ..Default::default()
+    };
+    let mut model = Model::new();
+    model.add_hook(move |event| filter.check(event));
+    let root_id = model.get_root().event.hash();
+
+    let spam = create_message(root_id, "alice", "spam", GENESIS_TIMESTAMP + 1).with_author(blocked);
+    let fine =
+        create_message(root_id, "alice", "fine", GENESIS_TIMESTAMP + 1).with_author([0xaa; 32]);
+    let (spam_id, fine_id) = (spam.hash(), fine.hash());
+    model.add(spam).await;
+    model.add(fine).await;
+    assert!(!model.event_map.contains_key(&spam_id));
+    assert!(model.event_map.contains_key(&fine_id));
+}
+
+// Each action in a batch is filtered and shown under its own nick
+async fn check_batch_nicks() {
+    let filter = AuthorFilter {
+        blocked_nicks: HashSet::from(["mallory".to_string()]),
+        ..Default::default()
+    };
+    let mut model = Model::new();
+    model.add_hook(move |event| filter.check(event));
+    let root_id = model.get_root().event.hash();
+
+    let batch = |nicks: [&str; 2]| {
+        let mut event = create_message(root_id, nicks[0], "hello", GENESIS_TIMESTAMP + 1);
+        let second = create_message(root_id, nicks[1], "hi", GENESIS_TIMESTAMP + 1);
+        event.action = EventAction::Batch(vec![event.action, second.action]);
+        event
+    };
+    let hidden = batch(["alice", "mallory"]);
+    let fine = batch(["alice", "bob"]);
+    let (hidden_id, fine_id) = (hidden.hash(), fine.hash());
+    model.add(hidden).await;
+    model.add(fine).await;
+    assert!(!model.event_map.contains_key(&hidden_id));
+
+    let view = View::new(ReplayOrder::Timestamp, 0);
+    let lines = view.format(&model.event_map[&fine_id].event);
+    assert_eq!(lines, "<alice> hello\n<bob> hi");
+}
+
+async fn check_nick_len() {
+    let mut model = Model::new();
+    model.add_hook(limit_nick_len(9));
+    let root_id = model.get_root().event.hash();
+
+    let short = create_message(root_id, "ninechars", "ok", GENESIS_TIMESTAMP + 1);
+    let long = create_message(root_id, "tencharsxx", "too long", GENESIS_TIMESTAMP + 1);
+    let (short_id, long_id) = (short.hash(), long.hash());
+    model.add(short).await;
+    model.add(long).await;
+    assert!(model.event_map.contains_key(&short_id));
+    assert!(!model.event_map.contains_key(&long_id));
 }
 
-pub const CONFIG_FILE: &str = "ircd_config.toml";
-pub const CONFIG_FILE_CONTENTS: &str = include_str!("../ircd_config.toml");
+async fn check_action_types() {
+    let mut model = Model::new();
+    model.add_hook(allow_action_types(HashSet::from([0])));
+    let root_id = model.get_root().event.hash();
 
-#[derive(Clone, Debug, serde::Deserialize, StructOpt, StructOptToml)]
-#[serde(default)]
-#[structopt(name = "ircd")]
-pub struct Args {
-    #[structopt(long)]
-    pub config: Option<String>,
+    let msg = create_message(root_id, "alice", "plain", GENESIS_TIMESTAMP + 1);
+    let mut batch = create_message(root_id, "alice", "batched", GENESIS_TIMESTAMP + 1);
+    batch.action = EventAction::Batch(vec![batch.action]);
+    let (msg_id, batch_id) = (msg.hash(), batch.hash());
+    model.add(msg).await;
+    model.add(batch).await;
+    assert!(model.event_map.contains_key(&msg_id));
+    assert!(!model.event_map.contains_key(&batch_id));
+}
 
-    /// Increase verbosity
-    #[structopt(short, parse(from_occurrences))]
-    pub verbose: u8,
+async fn check_read_only() {
+    let mut model = Model::new();
+    model.set_read_only(true);
+    let root_id = model.get_root().event.hash();
+
+    let local = create_message(root_id, "alice", "local", GENESIS_TIMESTAMP + 1);
+    let local_id = local.hash();
+    assert!(matches!(
+        model.add_from(local, Origin::Local).await,
+        Err(ModelError::ReadOnly)
+    ));
+    assert!(!model.event_map.contains_key(&local_id));
+
+    let relayed = create_message(root_id, "bob", "relayed", GENESIS_TIMESTAMP + 1);
+    let relayed_id = relayed.hash();
+    let peer = Origin::P2p("archive-peer".to_string());
+    model.add_from(relayed, peer).await.unwrap();
+    assert!(model.event_map.contains_key(&relayed_id));
+    let replayed: Vec<EventId> = model
+        .replay_all()
+        .iter()
+        .map(|node| node.event.hash())
+        .collect();
+    assert!(replayed.contains(&relayed_id));
+
+    // Quarantined before the node became read-only, readmitting must not
+    // sneak a local event in
+    let mut model = Model::new();
+    model.add_hook(limit_nick_len(5));
+    model.set_quarantine_size(2);
+    let local = create_message(root_id, "charlie", "local", GENESIS_TIMESTAMP + 1);
+    let relayed = create_message(root_id, "epsilon", "relayed", GENESIS_TIMESTAMP + 1);
+    let (local_id, relayed_id) = (local.hash(), relayed.hash());
+    let _ = model.add_from(local, Origin::Local).await;
+    let _ = model
+        .add_from(relayed, Origin::P2p("archive-peer".to_string()))
+        .await;
+    model.set_read_only(true);
+    model.clear_hooks();
+
+    assert_eq!(model.readmit(&local_id).await, Err(ModelError::ReadOnly));
+    assert_eq!(model.quarantined().count(), 2);
+    assert_eq!(model.readmit(&relayed_id).await, Ok(()));
+    assert!(model.event_map.contains_key(&relayed_id));
+    assert!(!model.event_map.contains_key(&local_id));
 }
 
-fn get_current_time() -> u64 {
-    let start = std::time::SystemTime::now();
-    start
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis()
-        .try_into()
-        .unwrap()
+async fn check_fork_diff() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    let tip_a = add_chain(&mut model, fork, "alice", 3).await;
+    let tip_b = add_chain(&mut model, fork, "bob", 2).await;
+
+    let node_a = model.event_map[&tip_a].clone();
+    let node_b = model.event_map[&tip_b].clone();
+    let (only_a, only_b) = model.fork_diff(node_a.clone(), node_b).unwrap();
+    let actions = |nodes: &[EventNodePtr]| -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| format!("{:?}", node.event.action))
+            .collect()
+    };
+    assert_eq!(
+        actions(&only_a),
+        [
+            "PRIVMSG alice: alice message 0",
+            "PRIVMSG alice: alice message 1",
+            "PRIVMSG alice: alice message 2"
+        ]
+    );
+    assert_eq!(
+        actions(&only_b),
+        ["PRIVMSG bob: bob message 0", "PRIVMSG bob: bob message 1"]
+    );
+
+    // A node against its own ancestor has only its own side
+    let (only_a, only_fork) = model
+        .fork_diff(node_a.clone(), model.event_map[&fork].clone())
+        .unwrap();
+    assert_eq!(only_a.len(), 3);
+    assert!(only_fork.is_empty());
+    let (same_a, same_b) = model.fork_diff(node_a.clone(), node_a).unwrap();
+    assert!(same_a.is_empty() && same_b.is_empty());
 }
 
-fn create_message(previous_event_hash: EventId, nick: &str, msg: &str, timestamp: u64) -> Event {
-    Event {
-        previous_event_hash,
+async fn check_chatlog() -> Result<()> {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let tip = add_chain(&mut model, root_id, "alice", 2).await;
+
+    let dir = std::env::temp_dir().join(format!("ircd-chatlog-{}", OsRng.next_u64()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("chat.log");
+
+    // The genesis line alone is over 10 bytes, so the second append rotates
+    let chatlog = ChatLog::new(path.clone(), 10);
+    let mut view = View::new(ReplayOrder::Timestamp, 0);
+    chatlog.append(&view.process(&model))?;
+    add_chain(&mut model, tip, "bob", 1).await;
+    chatlog.append(&view.process(&model))?;
+
+    let rotated = fs::read_to_string(dir.join("chat.log.1"))?;
+    assert_eq!(
+        rotated,
+        format!(
+            "[{}] <root> Let there be dark\n[{}] <alice> alice message 0\n[{}] <alice> alice message 1\n",
+            GENESIS_TIMESTAMP,
+            GENESIS_TIMESTAMP + 1,
+            GENESIS_TIMESTAMP + 2
+        )
+    );
+    let current = fs::read_to_string(&path)?;
+    assert_eq!(
+        current,
+        format!("[{}] <bob> bob message 0\n", GENESIS_TIMESTAMP + 1)
+    );
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+async fn check_import() {
+    let mut source = Model::new();
+    let root_id = source.get_root().event.hash();
+    let fork = add_chain(&mut source, root_id, "shared", 2).await;
+    add_chain(&mut source, fork, "alice", 2).await;
+    add_chain(&mut source, fork, "bob", 1).await;
+    let exported: Vec<Vec<u8>> = source
+        .replay_all()
+        .iter()
+        .filter(|node| node.event.hash() != root_id)
+        .map(|node| serialize(&node.event))
+        .collect();
+    let bundle = |range: std::ops::Range<usize>| -> Vec<Event> {
+        exported[range]
+            .iter()
+            .map(|bytes| deserialize(bytes).expect("decode failed"))
+            .collect()
+    };
+
+    // One bad event spoils the whole bundle
+    let mut model = Model::new();
+    let mut spoiled = bundle(0..5);
+    spoiled.push(create_message(
+        fork,
+        "mallory",
+        "bad\r\nQUIT",
+        GENESIS_TIMESTAMP + 9,
+    ));
+    let bad_id = spoiled[5].hash();
+    assert!(matches!(
+        model.import(spoiled).await,
+        Err(ModelError::RejectedEvent(event_id, _)) if event_id == bad_id
+    ));
+    assert_eq!(model.event_map.len(), 1);
+
+    // Without the shared chain the forks have nothing to attach to
+    assert!(matches!(
+        model.import(bundle(3..5)).await,
+        Err(ModelError::MissingParent(_))
+    ));
+    assert_eq!(model.event_map.len(), 1);
+
+    // Overlapping bundles only count what is new
+    assert_eq!(model.import(bundle(0..3)).await.expect("import failed"), 3);
+    assert_eq!(model.import(bundle(0..5)).await.expect("import failed"), 2);
+    assert_eq!(model.event_map.len(), 6);
+    assert_eq!(model.head.event.hash(), source.head.event.hash());
+    assert_eq!(
+        model.origin(&model.head.event.hash()),
+        Some(&Origin::Import)
+    );
+}
+
+async fn check_large_reorg() {
+    let mut model = Model::new();
+    model.set_large_reorg_threshold(2);
+    let root_id = model.get_root().event.hash();
+    let fork = add_chain(&mut model, root_id, "shared", 2).await;
+    add_chain(&mut model, fork, "alice", 2).await;
+
+    // Replaces at most the two alice events
+    add_chain(&mut model, fork, "bob", 3).await;
+    assert_eq!(model.stats().large_reorg_count, 0);
+
+    // Replaces the whole chain back to the root
+    add_chain(&mut model, root_id, "charlie", 6).await;
+    assert_eq!(model.head.event.nick(), "charlie");
+    assert_eq!(model.stats().large_reorg_count, 1);
+}
+
+async fn check_orphan_roots() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    // a has children b and c, and is waiting on x. d is waiting on y.
+    let x = create_message(root_id, "alice", "x", GENESIS_TIMESTAMP + 1);
+    let a = create_message(x.hash(), "alice", "a", GENESIS_TIMESTAMP + 2);
+    let b = create_message(a.hash(), "alice", "b", GENESIS_TIMESTAMP + 3);
+    let c = create_message(a.hash(), "bob", "c", GENESIS_TIMESTAMP + 3);
+    let y = create_message(root_id, "bob", "y", GENESIS_TIMESTAMP + 1);
+    let d = create_message(y.hash(), "bob", "d", GENESIS_TIMESTAMP + 2);
+    let (x_id, y_id, d_id) = (x.hash(), y.hash(), d.hash());
+    let mut cluster = vec![a.hash(), b.hash(), c.hash()];
+    cluster.sort();
+    for event in [c, d, b, a] {
+        model.add(event).await;
+    }
+
+    let roots = model.orphan_roots();
+    assert_eq!(roots.len(), 2);
+    assert_eq!(roots[&x_id], cluster);
+    assert_eq!(roots[&y_id], [d_id]);
+
+    // Fetching x resolves its whole cluster
+    model.add(x).await;
+    let roots = model.orphan_roots();
+    assert_eq!(roots.len(), 1);
+    assert!(roots.contains_key(&y_id));
+}
+
+async fn check_nick_disambiguation() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let timestamp = GENESIS_TIMESTAMP + 1;
+    let alice_1 = create_message(root_id, "alice", "one", timestamp).with_author([0x11; 32]);
+    let alice_2 =
+        create_message(alice_1.hash(), "alice", "two", timestamp + 1).with_author([0x22; 32]);
+    let bob = create_message(alice_2.hash(), "bob", "three", timestamp + 2).with_author([0x33; 32]);
+    let anonymous = create_message(bob.hash(), "alice", "four", timestamp + 3);
+    for event in [alice_1, alice_2, bob, anonymous] {
+        model.add(event).await;
+    }
+
+    let mut view = View::new(ReplayOrder::Timestamp, 0);
+    view.set_disambiguate_nicks(true);
+    let lines: Vec<String> = view
+        .process(&model)
+        .iter()
+        .map(|node| view.format(&node.event))
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "<root> Let there be dark",
+            "<alice[1111]> one",
+            "<alice[2222]> two",
+            "<bob> three",
+            "<alice> four"
+        ]
+    );
+
+    view.set_disambiguate_nicks(false);
+    let alice_2 = &view.transcript().nth(2).unwrap();
+    assert_eq!(view.format(&alice_2.event), "<alice> two");
+}
+
+async fn check_quarantine() {
+    let mut model = Model::new();
+    model.add_hook(limit_nick_len(5));
+    model.set_quarantine_size(2);
+    let root_id = model.get_root().event.hash();
+
+    let mut rejected = Vec::new();
+    for nick in ["charlie", "epsilon", "lambda"] {
+        let event = create_message(root_id, nick, "hi", GENESIS_TIMESTAMP + 1);
+        rejected.push(event.hash());
+        model.add(event).await;
+    }
+    assert_eq!(model.event_map.len(), 1);
+
+    // The oldest was evicted to stay within the bound
+    let held: Vec<EventId> = model
+        .quarantined()
+        .map(|entry| entry.event.hash())
+        .collect();
+    assert_eq!(held, rejected[1..]);
+    assert!(model
+        .quarantined()
+        .all(|entry| entry.reason == "nick longer than 5 characters"));
+
+    // The policy is relaxed, but not far enough for epsilon
+    model.clear_hooks();
+    model.add_hook(limit_nick_len(6));
+    model.readmit(&rejected[2]).await.expect("readmit failed");
+    assert_eq!(model.head.event.hash(), rejected[2]);
+    assert_eq!(
+        model.readmit(&rejected[1]).await,
+        Err(ModelError::RejectedEvent(
+            rejected[1],
+            "nick longer than 6 characters".to_string()
+        ))
+    );
+    let held: Vec<&str> = model
+        .quarantined()
+        .map(|entry| entry.reason.as_str())
+        .collect();
+    assert_eq!(held, ["nick longer than 6 characters"]);
+    assert!(matches!(
+        model.readmit(&rejected[0]).await,
+        Err(ModelError::UnknownEvent(_))
+    ));
+
+    // No policy change lets line breaks through
+    model.clear_hooks();
+    let injected = create_message(root_id, "alice", "hi\r\nQUIT", GENESIS_TIMESTAMP + 1);
+    let injected_id = injected.hash();
+    model.add(injected).await;
+    assert!(matches!(
+        model.readmit(&injected_id).await,
+        Err(ModelError::RejectedEvent(..))
+    ));
+    assert!(!model.event_map.contains_key(&injected_id));
+    assert!(model
+        .quarantined()
+        .any(|entry| entry.event.hash() == injected_id));
+}
+
+async fn check_walk_guards() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let tip = add_chain(&mut model, root_id, "alice", 2).await;
+    assert_eq!(
+        model.find_height(model.event_map[&tip].clone()).ok(),
+        Some(2)
+    );
+
+    // A cycle can't be built through Arc parents, so corrupt the model
+    // with a chain longer than the model that never reaches the root
+    let mut stray = Arc::new(EventNode {
+        parent: None,
+        event: create_message([0xff; 32], "mallory", "stray", 0),
+        children: Mutex::new(Vec::new()),
+        height: 0,
+    });
+    for i in 0..10 {
+        stray = Arc::new(EventNode {
+            parent: Some(stray.clone()),
+            event: create_message(stray.event.hash(), "mallory", "stray", i),
+            children: Mutex::new(Vec::new()),
+            height: stray.height + 1,
+        });
+    }
+    let stray_id = stray.event.hash();
+    model.event_map.insert(stray_id, stray.clone());
+
+    assert!(matches!(
+        model.find_height(stray.clone()),
+        Err(ModelError::BrokenAncestry(event_id)) if event_id == stray_id
+    ));
+    assert!(model
+        .find_ancestor_depth(stray.clone(), model.get_root())
+        .is_err());
+    assert_eq!(
+        model.ancestors(stray.clone()).count(),
+        model.event_map.len()
+    );
+    assert!(!model.is_ancestor(&root_id, &stray_id));
+    assert!(matches!(
+        model.common_ancestor(stray.clone(), model.get_root()),
+        Err(ModelError::BrokenAncestry(event_id)) if event_id == stray_id
+    ));
+    assert!(model
+        .dump(1)
+        .iter()
+        .any(|line| line.ends_with("[height=?]")));
+}
+
+async fn check_wait_for() {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    // Already present
+    let root = model.wait_for(root_id).await.expect("root missing");
+    assert_eq!(root.event.hash(), root_id);
+
+    // Arrives later, and only once its missing parent does
+    let parent = create_message(root_id, "alice", "parent", GENESIS_TIMESTAMP + 1);
+    let child = create_message(parent.hash(), "alice", "child", GENESIS_TIMESTAMP + 2);
+    let (parent_id, child_id) = (parent.hash(), child.hash());
+    let waiting = model.wait_for(child_id);
+    model.add(child).await;
+    model.add(parent).await;
+    assert_eq!(waiting.await.expect("child missing").event.hash(), child_id);
+
+    // Refused because it builds on a purged event
+    model.purge(&parent_id).await.expect("purge failed");
+    let refused = create_message(parent_id, "bob", "refused", GENESIS_TIMESTAMP + 3);
+    let waiting = model.wait_for(refused.hash());
+    model.add(refused).await;
+    assert!(waiting.await.is_none());
+
+    // Giving up on events that never arrive doesn't leave waiters behind
+    for i in 0..10u8 {
+        drop(model.wait_for([i; 32]));
+    }
+    let _waiting = model.wait_for([0xee; 32]);
+    assert_eq!(model.waiters.len(), 1);
+}
+
+// Header-first catch-up: node B learns the tree shape from headers alone,
+// then fetches only the payloads it is missing
+async fn check_header_sync() -> Result<()> {
+    let (mut node_a, mut node_b) = SimNode::pair();
+    let root_id = node_a.model.get_root().event.hash();
+
+    let fork = add_chain(&mut node_a.model, root_id, "shared", 3).await;
+    add_chain(&mut node_a.model, fork, "alice", 2).await;
+    add_chain(&mut node_a.model, fork, "bob", 4).await;
+
+    let headers: Vec<EventHeader> = node_a
+        .headers()
+        .iter()
+        .map(|header| deserialize(&serialize(header)))
+        .collect::<Result<_>>()?;
+
+    // Every header hangs off another header or something B already has
+    let inventory_b = node_b.inventory();
+    let header_ids: HashSet<EventId> = headers.iter().map(|header| header.id).collect();
+    for header in &headers {
+        if header.id != root_id {
+            assert!(
+                header_ids.contains(&header.previous_event_hash)
+                    || inventory_b.contains(&header.previous_event_hash)
+            );
+        }
+    }
+
+    let wanted: Vec<EventId> = headers
+        .iter()
+        .map(|header| header.id)
+        .filter(|id| !inventory_b.contains(id))
+        .collect();
+    assert_eq!(wanted.len(), 9);
+    node_a.push_events(&wanted).await;
+    node_b.receive_all().await?;
+
+    assert!(node_b.model.orphans.is_empty());
+    assert_eq!(node_a.inventory(), node_b.inventory());
+    assert_eq!(
+        node_a.model.find_head().await.event.hash(),
+        node_b.model.find_head().await.event.hash()
+    );
+    Ok(())
+}
+
+// Append a chain of `len` messages to `parent` and return the last ID
+async fn add_chain(model: &mut Model, mut parent: EventId, nick: &str, len: u32) -> EventId {
+    for i in 0..len {
+        let msg = format!("{} message {}", nick, i);
+        let event = create_message(parent, nick, &msg, GENESIS_TIMESTAMP + 1 + i as u64);
+        parent = event.hash();
+        model.add(event).await;
+    }
+    model.flush().await;
+    parent
+}
+
+// A length prefix past MAX_FIELD_LEN, or longer than the data, must fail
+// cleanly rather than allocate what it claims
+fn check_oversized_fields() {
+    let event = create_message([0; 32], "alice", "hello", GENESIS_TIMESTAMP + 1);
+    let encoded = serialize(&event);
+    // The nick's length prefix follows the parent hash and type byte
+    let (head, rest) = encoded.split_at(33);
+    let with_nick_len = |len: u64| {
+        let mut bytes = head.to_vec();
+        bytes.extend(serialize(&VarInt(len)));
+        bytes.extend(&rest[1..]);
+        bytes
+    };
+
+    for len in [u64::MAX, MAX_FIELD_LEN + 1, MAX_FIELD_LEN] {
+        assert!(matches!(
+            deserialize::<Event>(&with_nick_len(len)),
+            Err(darkfi::Error::ParseFailed(_))
+        ));
+    }
+    assert!(deserialize::<Event>(&with_nick_len(5)).is_ok());
+}
+
+// There are no NICK events, a nick change is just the author sending
+// under a new nick. Both sides of it must still trace to the same author.
+async fn check_nick_change() -> Result<()> {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+    let author: AuthorId = [0x42; 32];
+
+    let before = create_message(root_id, "alice", "brb", GENESIS_TIMESTAMP + 1).with_author(author);
+    let after = create_message(before.hash(), "alice_away", "back", GENESIS_TIMESTAMP + 2)
+        .with_author(author);
+    let event_ids = [before.hash(), after.hash()];
+    // Over the wire, as a peer would see them
+    for event in [before, after] {
+        model.add(deserialize(&serialize(&event))?).await;
+    }
+
+    let attributed: Vec<(&str, Option<AuthorId>)> = event_ids
+        .iter()
+        .map(|event_id| {
+            let event = &model.event_map[event_id].event;
+            (event.nick(), event.author)
+        })
+        .collect();
+    assert_eq!(
+        attributed,
+        [("alice", Some(author)), ("alice_away", Some(author))]
+    );
+    Ok(())
+}
+
+// How newly seen events are placed relative to ones already shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReplayOrder {
+    // Keep the transcript in strict timestamp order, so an event arriving
+    // late with an old timestamp lands before messages already shown
+    Timestamp,
+    // Once shown an event keeps its place. New events are ordered among
+    // themselves and appended.
+    AppendStable,
+}
+
+impl std::str::FromStr for ReplayOrder {
+    type Err = darkfi::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "timestamp" => Ok(Self::Timestamp),
+            "append-stable" => Ok(Self::AppendStable),
+            _ => Err(darkfi::Error::ParseFailed("Unknown replay order")),
+        }
+    }
+}
+
+// Known events with their exact encoding and ID. If one of these checks
+// fails the wire format changed, and nodes running the old format will no
+// longer be able to sync with this one.
+struct TestVector {
+    event: Event,
+    encoded: &'static str,
+    id: &'static str,
+}
+
+fn test_vectors() -> Vec<TestVector> {
+    let genesis_id = hex!("765364d5ebf3edbf4d9541d1c3c7a5d11d77e91523da5d53d19b78bf78fde9da");
+    let privmsg = |nick: &str, msg: &str, timestamp, nonce, author| Event {
+        previous_event_hash: genesis_id,
         action: EventAction::PrivMsg(PrivMsgEvent {
             nick: nick.to_string(),
             msg: msg.to_string(),
         }),
         timestamp,
+        nonce,
+        author,
+    };
+
+    vec![
+        TestVector {
+            event: genesis_event(),
+            encoded: concat!(
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "00",
+                "04726f6f74",
+                "114c6574207468657265206265206461726b",
+                "00b8d79083010000",
+                "0000000000000000",
+                "00",
+            ),
+            id: "765364d5ebf3edbf4d9541d1c3c7a5d11d77e91523da5d53d19b78bf78fde9da",
+        },
+        TestVector {
+            event: privmsg(
+                "alice",
+                "hello",
+                GENESIS_TIMESTAMP + 1,
+                0x0102030405060708,
+                None,
+            ),
+            encoded: concat!(
+                "765364d5ebf3edbf4d9541d1c3c7a5d11d77e91523da5d53d19b78bf78fde9da",
+                "00",
+                "05616c696365",
+                "0568656c6c6f",
+                "01b8d79083010000",
+                "0807060504030201",
+                "00",
+            ),
+            id: "49565bfb86925c761449b5730af60985002175d965a2d026156f4d20a8cdc1f1",
+        },
+        TestVector {
+            event: privmsg("bob", "hi", GENESIS_TIMESTAMP + 2, 42, Some([0x11; 32])),
+            encoded: concat!(
+                "765364d5ebf3edbf4d9541d1c3c7a5d11d77e91523da5d53d19b78bf78fde9da",
+                "00",
+                "03626f62",
+                "026869",
+                "02b8d79083010000",
+                "2a00000000000000",
+                "01",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+            ),
+            id: "1dfb8c70cadd140b45f91fe936ed055d38941b0c754299aa5446bf5d5a650f12",
+        },
+        TestVector {
+            event: Event {
+                action: EventAction::Batch(vec![
+                    privmsg("alice", "hello", 0, 0, None).action,
+                    privmsg("bob", "hi", 0, 0, None).action,
+                ]),
+                ..privmsg("", "", GENESIS_TIMESTAMP + 3, 7, None)
+            },
+            encoded: concat!(
+                "765364d5ebf3edbf4d9541d1c3c7a5d11d77e91523da5d53d19b78bf78fde9da",
+                "01",
+                "02",
+                "00",
+                "05616c696365",
+                "0568656c6c6f",
+                "00",
+                "03626f62",
+                "026869",
+                "03b8d79083010000",
+                "0700000000000000",
+                "00",
+            ),
+            id: "117536c95f3b255173a8750a9375e62ee536d23ada97961674286d3c202f03ce",
+        },
+    ]
+}
+
+fn check_test_vectors() -> Result<()> {
+    for vector in test_vectors() {
+        let encoded = serialize(&vector.event);
+        assert_eq!(hex::encode(&encoded), vector.encoded);
+        assert_eq!(hex::encode(vector.event.hash()), vector.id);
+
+        // And it must decode back to the same event
+        let decoded: Event = deserialize(&encoded)?;
+        assert_eq!(hex::encode(decoded.hash()), vector.id);
+    }
+    Ok(())
+}
+
+// A batch must survive the wire intact and land in the model as one node
+async fn check_batch() -> Result<()> {
+    let mut model = Model::new();
+    let root_id = model.get_root().event.hash();
+
+    let batch = Event {
+        previous_event_hash: root_id,
+        action: EventAction::Batch(vec![
+            create_message(root_id, "alice", "first", 0).action,
+            create_message(root_id, "alice", "second", 0).action,
+        ]),
+        timestamp: get_current_time(),
+        nonce: OsRng.next_u64(),
+        author: None,
+    };
+    let batch: Event = deserialize(&serialize(&batch))?;
+    let batch_id = batch.hash();
+    model.add(batch).await;
+
+    let head = model.find_head().await;
+    assert_eq!(head.event.hash(), batch_id);
+    assert_eq!(head.height, 1);
+    match &head.event.action {
+        EventAction::Batch(actions) => {
+            let msgs: Vec<_> = actions
+                .iter()
+                .map(|action| format!("{:?}", action))
+                .collect();
+            assert_eq!(msgs, ["PRIVMSG alice: first", "PRIVMSG alice: second"]);
+        }
+        _ => panic!("batch decoded as a single action"),
+    }
+
+    // Nested batches are refused
+    let mut nested = vec![1u8, 1];
+    nested.extend(serialize(&head.event.action));
+    assert!(deserialize::<EventAction>(&nested).is_err());
+
+    Ok(())
+}
+
+fn check_signatures(identity: &SigningKey) -> Result<()> {
+    let author_id: AuthorId = identity.verifying_key().to_bytes();
+    let event = create_message([0u8; 32], "alice", "signed", 0).with_author(author_id);
+
+    let signature = EventSignature::sign(SignatureScheme::Ed25519, &event, identity)?;
+    let signature: EventSignature = deserialize(&serialize(&signature))?;
+    assert!(signature.verify(&event, &author_id));
+    assert!(EventSignature::sign(SignatureScheme::Secp256k1, &event, identity).is_err());
+
+    // Same bytes claimed under another scheme
+    let retagged = EventSignature {
+        scheme: SignatureScheme::Secp256k1,
+        bytes: signature.bytes.clone(),
+    };
+    assert!(!retagged.verify(&event, &author_id));
+
+    // Another event, or another author
+    let other = create_message([0u8; 32], "alice", "other", 0).with_author(author_id);
+    assert!(!signature.verify(&other, &author_id));
+    let stranger = SigningKey::generate(&mut OsRng).verifying_key().to_bytes();
+    assert!(!signature.verify(&event, &stranger));
+
+    // Unknown scheme bytes don't decode
+    let mut encoded = serialize(&signature);
+    encoded[0] = 0xff;
+    assert!(deserialize::<EventSignature>(&encoded).is_err());
+    Ok(())
+}
+
+async fn check_pow() {
+    let mut model = Model::new();
+    model.add_hook(require_pow(8));
+    let root_id = model.get_root().event.hash();
+
+    let mined = create_message(root_id, "alice", "mined", GENESIS_TIMESTAMP + 1).mined(8);
+    assert!(leading_zero_bits(&mined.hash()) >= 8);
+    let mut weak = create_message(root_id, "bob", "weak", GENESIS_TIMESTAMP + 1);
+    while leading_zero_bits(&weak.hash()) >= 8 {
+        weak.nonce += 1;
     }
+    let (mined_id, weak_id) = (mined.hash(), weak.hash());
+
+    model.add(mined).await;
+    model.add(weak).await;
+    assert!(model.event_map.contains_key(&mined_id));
+    assert!(!model.event_map.contains_key(&weak_id));
+    assert_eq!(leading_zero_bits(&[0u8; 32]), 256);
 }
 
 struct View {
     seen: HashSet<EventId>,
+    // Next Model::inserted_since() sequence number to read
+    cursor: u64,
+    // Every event shown so far, in display order. Keyed by (batch,
+    // timestamp, ID), where batch counts record() calls for AppendStable
+    // and is always 0 for Timestamp.
+    transcript: BTreeMap<(u64, u64, EventId), EventNodePtr>,
+    batches: u64,
+    order: ReplayOrder,
+    // Events older than this many milliseconds are never replayed. 0 means
+    // no limit. They stay in the model for sync until pruned.
+    message_ttl: u64,
+    // Nicks aren't authenticated, so different authors can share one. If
+    // set, such nicks are shown with a short author ID suffix.
+    disambiguate_nicks: bool,
+    // Authors seen using each nick so far
+    nick_authors: HashMap<String, HashSet<AuthorId>>,
 }
 
 impl View {
-    fn new() -> Self {
+    fn new(order: ReplayOrder, message_ttl: u64) -> Self {
         Self {
-            seen: HashSet::new()
+            seen: HashSet::new(),
+            cursor: 0,
+            transcript: BTreeMap::new(),
+            batches: 0,
+            order,
+            message_ttl,
+            disambiguate_nicks: false,
+            nick_authors: HashMap::new(),
         }
     }
 
-    fn process(model: &Model) {
+    fn set_disambiguate_nicks(&mut self, disambiguate_nicks: bool) {
+        self.disambiguate_nicks = disambiguate_nicks;
+    }
+
+    // Returns the events not seen before, ordered by timestamp, and adds
+    // them to the transcript according to the replay order
+    fn process(&mut self, model: &Model) -> Vec<EventNodePtr> {
         // This does 2 passes:
-        // 1. Walk down all chains and get unseen events
+        // 1. Take the events inserted since the last call
         // 2. Order those events according to timestamp
         // Then the events are replayed to the IRC client
+        let oldest_timestamp = self.oldest_timestamp();
+
+        let mut unseen = Vec::new();
+        for (seq, node) in model.inserted_since(self.cursor) {
+            self.cursor = seq + 1;
+
+            // Expired events are marked seen too so they never show up later
+            if !self.seen.insert(node.event.hash()) || node.event.timestamp < oldest_timestamp {
+                continue;
+            }
+            unseen.push(node.clone());
+        }
+
+        unseen.sort_by_cached_key(|node| (node.event.timestamp, node.event.hash()));
+        self.record(&unseen);
+        unseen
+    }
+
+    // After the head moves onto another fork, returns the events on the new
+    // winning chain the client hasn't been sent. The prefix both heads
+    // share is skipped without being walked, as is anything on the new
+    // branch this view already showed.
+    fn process_reorg(&mut self, model: &Model, old_head: &EventId) -> Vec<EventNodePtr> {
+        let new_head = model.head.clone();
+        let ancestor = model
+            .event_map
+            .get(old_head)
+            .and_then(|old_head| {
+                model
+                    .common_ancestor(old_head.clone(), new_head.clone())
+                    .ok()
+            })
+            // Purged since or broken, so there is no fork point to start from
+            .unwrap_or_else(|| model.get_root());
+
+        let ancestor_id = ancestor.event.hash();
+        let oldest_timestamp = self.oldest_timestamp();
+        // Expired events are marked seen here as well
+        let unseen: Vec<EventNodePtr> = model
+            .events_between(&ancestor_id, new_head)
+            .into_iter()
+            .filter(|node| {
+                self.seen.insert(node.event.hash()) && node.event.timestamp >= oldest_timestamp
+            })
+            .collect();
+        self.record(&unseen);
+        unseen
+    }
+
+    // Events with a timestamp below this are past message_ttl
+    fn oldest_timestamp(&self) -> u64 {
+        match self.message_ttl {
+            0 => 0,
+            ttl => get_current_time().saturating_sub(ttl),
+        }
+    }
+
+    fn record(&mut self, events: &[EventNodePtr]) {
+        for node in events {
+            if let Some(author) = node.event.author {
+                for nick in node.event.action.nicks() {
+                    self.nick_authors
+                        .entry(nick.to_string())
+                        .or_default()
+                        .insert(author);
+                }
+            }
+        }
+        let batch = match self.order {
+            ReplayOrder::Timestamp => 0,
+            ReplayOrder::AppendStable => self.batches,
+        };
+        for node in events {
+            let key = (batch, node.event.timestamp, node.event.hash());
+            self.transcript.insert(key, node.clone());
+        }
+        self.batches += 1;
+    }
+
+    fn transcript(&self) -> impl ExactSizeIterator<Item = &EventNodePtr> {
+        self.transcript.values()
+    }
+
+    // One "<nick> msg" line per message in the event
+    fn format(&self, event: &Event) -> String {
+        let mut lines = Vec::new();
+        self.format_action(&event.action, event.author, &mut lines);
+        lines.join("\n")
+    }
+
+    fn format_action(
+        &self,
+        action: &EventAction,
+        author: Option<AuthorId>,
+        lines: &mut Vec<String>,
+    ) {
+        match action {
+            EventAction::PrivMsg(event) => {
+                let nick = self.display_nick(&event.nick, author);
+                lines.push(format!("<{}> {}", nick, event.msg))
+            }
+            EventAction::Batch(actions) => {
+                for action in actions {
+                    self.format_action(action, author, lines);
+                }
+            }
+        }
+    }
+
+    // alice becomes alice[a1b2] once a second author has been seen using
+    // it. Events without an author keep the bare nick.
+    fn display_nick(&self, nick: &str, author: Option<AuthorId>) -> String {
+        let is_shared = self
+            .nick_authors
+            .get(nick)
+            .is_some_and(|authors| authors.len() > 1);
+        match author {
+            Some(author) if self.disambiguate_nicks && is_shared => {
+                format!("{}[{}]", nick, hex::encode(&author[..2]))
+            }
+            _ => nick.to_string(),
+        }
     }
 }
 
-async_daemonize!(realmain);
-async fn realmain(settings: Args, executor: Arc<Executor<'_>>) -> Result<()> {
+// Plain text transcript for grepping, written in the order the View
+// replays messages. Not read back, the model stays the source of truth.
+struct ChatLog {
+    path: std::path::PathBuf,
+    // Once the file reaches this size it is moved to <path>.1, replacing
+    // any older one, and a new file is started. 0 never rotates.
+    max_size: u64,
+}
+
+impl ChatLog {
+    fn new(path: std::path::PathBuf, max_size: u64) -> Self {
+        Self { path, max_size }
+    }
+
+    fn append(&self, nodes: &[EventNodePtr]) -> Result<()> {
+        let mut lines = String::new();
+        for node in nodes {
+            Self::format(&node.event.action, node.event.timestamp, &mut lines);
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        if self.max_size > 0 {
+            let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+            if size >= self.max_size {
+                let mut rotated = self.path.clone().into_os_string();
+                rotated.push(".1");
+                fs::rename(&self.path, rotated)?;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(lines.as_bytes())?;
+        Ok(())
+    }
+
+    fn format(action: &EventAction, timestamp: u64, lines: &mut String) {
+        match action {
+            EventAction::PrivMsg(event) => {
+                lines.push_str(&format!("[{}] <{}> {}\n", timestamp, event.nick, event.msg))
+            }
+            EventAction::Batch(actions) => {
+                for action in actions {
+                    Self::format(action, timestamp, lines);
+                }
+            }
+        }
+    }
+}
+
+// Regression checks for the model, sync and view, run by --selftest. A
+// failing check panics, so the process exits nonzero.
+async fn selftest() -> Result<()> {
+    check_demo().await;
+    check_test_vectors()?;
+    check_batch().await?;
+    check_signatures(&SigningKey::generate(&mut OsRng))?;
+    check_pow().await;
+
+    let scenarios = [
+        SyncScenario {
+            shared: 3,
+            branch_a: 2,
+            branch_b: 4,
+        },
+        SyncScenario {
+            shared: 0,
+            branch_a: 5,
+            branch_b: 5,
+        },
+        SyncScenario {
+            shared: 10,
+            branch_a: 1,
+            branch_b: 0,
+        },
+        SyncScenario {
+            shared: 1,
+            branch_a: 20,
+            branch_b: 19,
+        },
+    ];
+    for scenario in &scenarios {
+        scenario.run().await?;
+    }
+    check_oversized_fields();
+    check_nick_change().await?;
+    check_is_ancestor().await;
+    check_orphan_cascade(false).await;
+    check_orphan_cascade(true).await;
+    check_batched_insert().await?;
+    bench_bulk_insert(10_000).await?;
+    check_identity()?;
+    check_stats().await;
+    check_nonce().await;
+    check_replay_order().await;
+    check_hooks().await;
+    check_forbidden_chars().await;
+    check_tip_summary().await;
+    check_purge().await?;
+    check_compare_head().await;
+    check_header_sync().await?;
+    check_wait_for().await;
+    check_prune_losing_forks().await;
+    check_inclusion_proof().await;
+    check_message_ttl().await;
+    check_reorg_delivery().await;
+    check_incremental_view().await;
+    bench_view_delivery(300).await;
+    check_rebuild_index().await;
+    check_ancestors_missing().await;
+    check_dump().await;
+    check_author_filter().await;
+    check_batch_nicks().await;
+    check_nick_len().await;
+    check_action_types().await;
+    check_read_only().await;
+    check_fork_diff().await;
+    check_chatlog().await?;
+    check_import().await;
+    check_large_reorg().await;
+    check_orphan_roots().await;
+    check_nick_disambiguation().await;
+    check_quarantine().await;
+    check_walk_guards().await;
+    Ok(())
+}
+
+// The original hand-built scenario: alice, bob and charlie fork off the
+// root, delta puts bob's branch ahead, then epsilon and phi overtake it
+async fn check_demo() {
     let mut model = Model::new();
     let root_id = model.get_root().event.hash();
 
@@ -360,7 +3774,84 @@ async fn realmain(settings: Args, executor: Arc<Executor<'_>>) -> Result<()> {
 
     assert_eq!(model.find_head().await.event.hash(), node6_id);
 
+    // delta's branch draws level, and the later timestamp wins the tie
+    let node7 = create_message(node4_id, "gamma", "gamma message", timestamp + 1);
+    let node7_id = node7.hash();
+    model.add(node7).await;
+    assert_eq!(model.find_head().await.event.hash(), node7_id);
+
+    // Then both branches grow by one at the same timestamp, so the event
+    // ID settles it
+    let node8 = create_message(node7_id, "kappa", "kappa message", timestamp + 2);
+    let node8_id = node8.hash();
+    model.add(node8).await;
+    let node9 = create_message(node6_id, "lambda", "lambda message", timestamp + 2);
+    let node9_id = node9.hash();
+    model.add(node9).await;
+    assert_eq!(model.find_head().await.event.hash(), node8_id.max(node9_id));
+    assert_eq!(model.head.event.hash(), node8_id.max(node9_id));
+
+    model.debug().await;
+}
+
+async_daemonize!(realmain);
+async fn realmain(settings: Args, executor: Arc<Executor<'_>>) -> Result<()> {
+    if settings.selftest {
+        selftest().await?;
+        info!("Self-test passed");
+        return Ok(());
+    }
+
+    let signature_scheme: SignatureScheme = settings.signature_scheme.parse()?;
+    let identity = load_identity(signature_scheme, &expand_path(&settings.identity_path)?)?;
+    let author_id: AuthorId = identity.verifying_key().to_bytes();
+    info!("Our author ID: {}", hex::encode(author_id));
+
+    // An event ID only has 256 bits, anything more could never be mined
+    if settings.pow_difficulty > 256 {
+        return Err(darkfi::Error::ParseFailed(
+            "pow_difficulty must be between 0 and 256",
+        ));
+    }
+
+    let mut model = Model::new();
+    model.set_large_reorg_threshold(settings.large_reorg_threshold);
+    model.set_quarantine_size(settings.quarantine_size);
+    model.set_read_only(settings.read_only);
+    let filter = AuthorFilter::from_settings(&settings)?;
+    model.add_hook(move |event| filter.check(event));
+    model.add_hook(limit_nick_len(settings.max_nick_len));
+    if !settings.relay_action_types.is_empty() {
+        let allowed = settings.relay_action_types.iter().copied().collect();
+        model.add_hook(allow_action_types(allowed));
+    }
+    let pow = settings.pow_difficulty;
+    if pow > 0 {
+        model.add_hook(require_pow(pow));
+    }
+
     model.debug().await;
 
+    let replay_order = settings.replay_order.parse()?;
+    let mut view = View::new(replay_order, settings.message_ttl.saturating_mul(1000));
+    view.set_disambiguate_nicks(settings.disambiguate_nicks);
+    let replayed = view.process(&model);
+    if !settings.chatlog_path.is_empty() {
+        let path = expand_path(&settings.chatlog_path)?;
+        ChatLog::new(path, settings.chatlog_max_size).append(&replayed)?;
+    }
+    for node in view.transcript() {
+        println!("replay: {}", view.format(&node.event));
+    }
+
     Ok(())
 }
+
+// Runs the same checks as --selftest under cargo test
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn selftest() {
+        smol::block_on(super::selftest()).expect("self-test failed");
+    }
+}